@@ -0,0 +1,260 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use tater::{Tater, Garden, Value, Error, TaterError};
+
+const HELP: &'static str = "
+    The program is paused before the instruction shown; an empty line
+    repeats the last command.
+
+    Commands:
+        step, s                 [runs one instruction]
+        next, n                 [runs one instruction, stepping over calls]
+        finish, f               [runs until the current call returns]
+        continue, c             [runs until a breakpoint or the end]
+        break, b <label>        [breaks on the instruction after a label]
+        break, b [file:]<line>  [breaks on the instruction of a line]
+        delete, d [number]      [deletes a breakpoint, or all of them]
+        breakpoints             [lists breakpoints]
+        print, p <value>        [evaluates a value, such as a pointer]
+        where, w                [shows the current instruction and calls]
+        stack [bits]            [prints stack as a sequence of bytes]
+        help                    [display this message]
+        quit, q                 [stop debugging]
+";
+
+struct Breakpoint {
+    name: String,
+    indices: Vec<usize>,
+}
+
+pub struct Debugger<'a> {
+    tater: &'a mut Tater,
+    garden: &'a mut Garden,
+    breakpoints: Vec<Breakpoint>,
+    finished: bool,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(tater: &'a mut Tater, garden: &'a mut Garden) -> Debugger<'a> {
+        Debugger {
+            tater: tater,
+            garden: garden,
+            breakpoints: Vec::new(),
+            finished: false,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), TaterError> {
+        self.tater.link()?;
+        self.finished = self.tater.len() == 0;
+
+        println!("[The Tater Language] Debugging, type 'help' for help.");
+        self.show();
+
+        let mut last = String::new();
+
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().ok();
+
+            let mut line = String::new();
+            let stdin = io::stdin();
+
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {},
+            }
+
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+
+            if !self.command(&line) {
+                break
+            }
+            last = line;
+        }
+        Ok(())
+    }
+
+    // Returns false when debugging should stop.
+    fn command(&mut self, line: &str) -> bool {
+        let name = line.split_whitespace().next().unwrap_or("");
+        let arg = line[name.len() ..].trim();
+
+        match name {
+            "step" | "s" => self.resume(|_| true),
+            "next" | "n" => {
+                let depth = self.garden.call_stack().len();
+                self.resume(|garden| garden.call_stack().len() <= depth);
+            },
+            "finish" | "f" => {
+                let depth = self.garden.call_stack().len();
+                self.resume(|garden| garden.call_stack().len() < depth);
+            },
+            "continue" | "c" => self.resume(|_| false),
+            "break" | "b" => self.add_breakpoint(arg),
+            "delete" | "d" => match arg {
+                "" => self.breakpoints.clear(),
+                arg => match usize::from_str(arg) {
+                    Ok(n) if n >= 1 && n <= self.breakpoints.len() => {
+                        self.breakpoints.remove(n - 1);
+                    },
+                    _ => eprintln!("No breakpoint number '{}'!", arg),
+                },
+            },
+            "breakpoints" => for (i, point) in self.breakpoints.iter().enumerate() {
+                println!("{}: {} (instruction(s) {:?})", i + 1, point.name, point.indices);
+            },
+            "print" | "p" => self.print(arg),
+            "where" | "w" => {
+                self.show();
+                for ret in self.garden.call_stack().iter().rev() {
+                    match self.tater.span(ret - 1) {
+                        Some(span) => println!("    called from {}: {} >>> {}", ret - 1, span, span.text),
+                        None => println!("    called from {}", ret - 1),
+                    }
+                }
+            },
+            "stack" => match usize::from_str(if arg == "" { "64" } else { arg }) {
+                Ok(bits) if bits > 0 => self.garden.print_bytes(bits),
+                _ => eprintln!("'stack' argument is invalid!"),
+            },
+            "help" => println!("{}", HELP),
+            "quit" | "q" => return false,
+            other => eprintln!("Unknown command '{}', type 'help' for help!", other),
+        }
+        true
+    }
+
+    // Runs instructions until 'stop' holds after one of them, a breakpoint
+    // is reached or the program ends.
+    fn resume<F>(&mut self, stop: F) where F: Fn(&Garden) -> bool {
+        if self.finished {
+            println!("The program has finished.");
+            return
+        }
+
+        loop {
+            match self.tater.step(self.garden) {
+                Ok(true) => {},
+                Ok(false) => {
+                    self.finished = true;
+                    println!("The program has finished.");
+                    return
+                },
+                Err(e) => {
+                    self.finished = true;
+                    eprintln!("{}", e);
+                    return
+                },
+            }
+
+            let index = self.garden.instruction;
+
+            if index >= self.tater.len() {
+                self.finished = true;
+                println!("The program has finished.");
+                return
+            }
+
+            if let Some(n) = self.breakpoints.iter().position(|p| p.indices.contains(&index)) {
+                println!("Breakpoint {}, {}.", n + 1, self.breakpoints[n].name);
+                break
+            }
+
+            if stop(self.garden) {
+                break
+            }
+        }
+        self.show();
+    }
+
+    fn show(&self) {
+        let index = self.garden.instruction;
+        match self.tater.span(index) {
+            Some(span) => println!("{}: {} >>> {}", index, span, span.text),
+            None => println!("{}: <end of program>", index),
+        }
+    }
+
+    fn add_breakpoint(&mut self, arg: &str) {
+        if arg == "" {
+            eprintln!("'break' needs a label or line!");
+            return
+        }
+
+        let indices: Vec<usize> = match self.tater.labels.get(arg) {
+            Some(index) => vec![*index],
+            None => {
+                let (file, line) = match arg.rfind(':') {
+                    Some(p) => (Some(&arg[.. p]), &arg[p + 1 ..]),
+                    None => (None, arg),
+                };
+
+                let line = match usize::from_str(line) {
+                    Ok(line) => line,
+                    Err(_) => {
+                        eprintln!("No label or line '{}'!", arg);
+                        return
+                    },
+                };
+
+                (0 .. self.tater.len()).filter(|i| {
+                    let span = self.tater.span(*i).unwrap();
+                    let same_file = match (file, &span.file) {
+                        (Some(file), &Some(ref path)) => path == file || path.ends_with(&format!("/{}", file)),
+                        (Some(_), &None) => false,
+                        (None, _) => true,
+                    };
+                    same_file && span.line == line
+                }).collect()
+            },
+        };
+
+        if indices.is_empty() {
+            eprintln!("No instructions at '{}'!", arg);
+            return
+        }
+
+        println!("Breakpoint {} at instruction(s) {:?}.", self.breakpoints.len() + 1, indices);
+        self.breakpoints.push(Breakpoint {
+            name: arg.to_string(),
+            indices: indices,
+        });
+    }
+
+    fn print(&self, arg: &str) {
+        let err = Error::new(arg.to_string(), 0, 1, None);
+
+        let value = match Value::new(arg, &err, false) {
+            Ok(value) => value,
+            Err(e) => {
+                eprintln!("{}", e.kind);
+                return
+            },
+        };
+
+        let num = match value.get_bignum(self.garden) {
+            Ok(num) => num,
+            Err(e) => {
+                eprintln!("{}", e);
+                return
+            },
+        };
+
+        match (value.get_ptr_position(self.garden), value.get_ptr_size(self.garden)) {
+            (Ok(pos), Ok(size)) => {
+                let bits: String = match self.garden.slice(pos, pos + size) {
+                    Ok(slice) => slice.iter().map(|b| if b { '1' } else { '0' }).collect(),
+                    Err(_) => String::new(),
+                };
+                println!("{} = {} (bits {}->{}: b{})", arg, num, pos, pos + size, bits);
+            },
+            _ => println!("{} = {}", arg, num),
+        }
+    }
+}
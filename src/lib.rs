@@ -0,0 +1,26 @@
+//! The Tater interpreter as a library.
+//!
+//! A `Tater` holds a parsed program, a `Garden` holds the state it runs on:
+//!
+//! ```no_run
+//! use tater::{Tater, Garden};
+//!
+//! let mut tater = Tater::new(false);
+//! tater.parse_file("test.tat").unwrap();
+//!
+//! let mut garden = Garden::new();
+//! tater.run(&mut garden).unwrap();
+//! ```
+
+mod tater;
+
+pub use tater::tater::{Tater, Checkpoint};
+pub use tater::garden::{Garden, Allocation};
+pub use tater::dump::{Dump, Format};
+pub use tater::format::{Source, Line, Item, format_source};
+pub use tater::bits::BitSlice;
+pub use tater::value::Value;
+pub use tater::instruction::Instruction;
+pub use tater::bytecode::{Program, Code, Op, Operand, Constant, Arith, Logic, Compare};
+pub use tater::image::{Image, VERSION as IMAGE_VERSION};
+pub use tater::error::{Error, ErrorType, ArgumentType, TaterError, Span};
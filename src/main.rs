@@ -1,154 +1,530 @@
+extern crate tater;
+
+mod repl;
+mod debug;
+
 use std::env;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str::FromStr;
 
-use tater::tater::Tater;
-use tater::garden::Garden;
+use tater::{Tater, Garden, Dump, Format, TaterError, ErrorType, format_source};
+use repl::Repl;
+use debug::Debugger;
 
 use std::collections::HashMap;
 
-mod tater;
-
-fn load_file(tater: &mut Tater, path: &str) {
-    let file = File::open(path).unwrap();
-    let buffer = BufReader::new(&file);
-
-    let mut line: usize = 0usize;
-
-    for ln in buffer.lines() {
-        let l: String = ln.unwrap();
-
-        line += 1usize;
-
-        tater.parse_line(&l, line, Some(path.to_string()));
+fn or_exit<T>(result: Result<T, TaterError>) -> T {
+    match result {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1)
+        }
     }
 }
 
+// Mistakes in the command line itself exit with 2, unlike program errors.
+fn usage_error(command: &Command, message: &str) -> ! {
+    eprintln!("tater {}: {}!", command.name, message);
+    eprintln!("Type 'tater {} --help' for help on how to use it.", command.name);
+    process::exit(2)
+}
+
 enum Req {
     Yes, Maybe, No,
 }
 
 struct ArgType {
-    name: String,
-    short: Option<String>,
+    name: &'static str,
+    short: Option<char>,
     arg: Req,
+    value: &'static str,
+    help: &'static str,
 }
 
-fn main() {
-    let valid_args = vec![
-        ArgType {
-            name: "help".to_string(),
-            short: Some("h".to_string()),
-            arg: Req::No,
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    about: &'static str,
+    files: (usize, usize),
+    args: Vec<ArgType>,
+}
+
+struct Args {
+    options: HashMap<String, String>,
+    files: Vec<String>,
+    defines: Vec<(String, String)>,
+    includes: Vec<String>,
+}
+
+fn arg(name: &'static str, short: Option<char>, arg: Req, value: &'static str, help: &'static str) -> ArgType {
+    ArgType {
+        name: name,
+        short: short,
+        arg: arg,
+        value: value,
+        help: help,
+    }
+}
+
+fn commands() -> Vec<Command> {
+    let seed = || arg("seed", None, Req::Yes, "<number>", "seeds the random number generator");
+    let print_parsed = || arg("print-parsed", Some('p'), Req::No, "", "prints each line as it's parsed");
+    let define = || arg("define", Some('D'), Req::Yes, "<name[=value]>", "defines 'name' before parsing, as 1 by default");
+    let include = || arg("include", Some('I'), Req::Yes, "<dir>", "looks for required files in 'dir' too");
+
+    vec![
+        Command {
+            name: "run",
+            usage: "tater run [options] <file>",
+            about: "Parses, links and runs a program, or runs a built image.",
+            files: (1, 1),
+            args: vec![
+                arg("file", Some('f'), Req::Yes, "<file>", "the program to run, same as giving it directly"),
+                arg("print-stack", Some('s'), Req::Maybe, "[bits]", "prints the stack afterwards, in chunks of 'bits' (64)"),
+                arg("format", None, Req::Yes, "<format>", "unsigned, signed, hex, binary or ascii"),
+                arg("range", None, Req::Yes, "<start:end>", "only prints the given bits of the stack"),
+                arg("offsets", None, Req::No, "", "prints each chunk on a line after its offset"),
+                arg("annotate", None, Req::No, "", "prints what pushed each part of the stack"),
+                seed(),
+                print_parsed(),
+                define(),
+                include(),
+                arg("trace", Some('t'), Req::No, "", "logs every instruction run to stderr"),
+                arg("debug", Some('d'), Req::No, "", "steps through the program in a debugger"),
+            ],
+        },
+        Command {
+            name: "check",
+            usage: "tater check [options] <file>...",
+            about: "Parses and links programs without running them.",
+            files: (1, usize::max_value()),
+            args: vec![
+                print_parsed(),
+                define(),
+                include(),
+            ],
         },
-        ArgType {
-            name: "file".to_string(),
-            short: Some("f".to_string()),
-            arg: Req::Yes,
+        Command {
+            name: "build",
+            usage: "tater build [options] <file>",
+            about: "Compiles a program to an image that 'tater run' loads without requiring, defines or macros.",
+            files: (1, 1),
+            args: vec![
+                arg("output", Some('o'), Req::Yes, "<file>", "where to write the image, the file with '.tatc' by default"),
+                define(),
+                include(),
+            ],
         },
-        ArgType {
-            name: "print-stack".to_string(),
-            short: Some("s".to_string()),
-            arg: Req::Maybe,
+        Command {
+            name: "disasm",
+            usage: "tater disasm [options] <file>",
+            about: "Prints a program back as source, with its labels and instruction indices.",
+            files: (1, 1),
+            args: vec![
+                define(),
+                include(),
+            ],
         },
-        ArgType {
-            name: "print-parsed".to_string(),
-            short: Some("p".to_string()),
-            arg: Req::No,
+        Command {
+            name: "fmt",
+            usage: "tater fmt [options] <file>...",
+            about: "Rewrites programs in the canonical style.",
+            files: (1, usize::max_value()),
+            args: vec![
+                arg("check", None, Req::No, "", "only lists files that aren't formatted, failing if any"),
+            ],
         },
-    ];
+        Command {
+            name: "repl",
+            usage: "tater repl [options] [file]",
+            about: "Starts an interactive repl, after running 'file' if given.",
+            files: (0, 1),
+            args: vec![
+                seed(),
+                print_parsed(),
+                define(),
+                include(),
+            ],
+        },
+    ]
+}
+
+fn print_help(commands: &[Command]) {
+    println!("
+    [The Tater Language]
 
-    let mut args:HashMap<String, String> = HashMap::new();
+    Usage:
+        tater <command> [options] [file]...
+        tater [options] <file>     [same as 'tater run']
+        tater                      [same as 'tater repl']
+        tater --repl [file]        [same as 'tater repl']
 
-    let mut current_arg_name: String = "file".to_string();
-    let mut is_first: bool = true;
+    Commands:");
 
-    for e in env::args().collect::<Vec<String>>() {
-        if is_first {
-            is_first = false;
-            continue;
+    for command in commands {
+        println!("        {:<10} {}", command.name, command.about);
+    }
+
+    println!("
+    Type 'tater <command> --help' for the options of a command.
+    ");
+}
+
+fn print_command_help(command: &Command) {
+    println!("
+    {}
+
+    Usage:
+        {}
+
+    Options:", command.about, command.usage);
+
+    for a in &command.args {
+        let short = match a.short {
+            Some(c) => format!("-{}, ", c),
+            None => "    ".to_string(),
+        };
+        println!("        {}--{:<22} [{}]", short, format!("{} {}", a.name, a.value), a.help);
+    }
+    println!("        -h, --{:<22} [display this message]\n", "help");
+}
+
+fn parse_args(command: &Command, raw: &[String]) -> Args {
+    let mut args = Args {
+        options: HashMap::new(),
+        files: Vec::new(),
+        defines: Vec::new(),
+        includes: Vec::new(),
+    };
+
+    let mut i = 0;
+    while i < raw.len() {
+        let e = &raw[i];
+        i += 1;
+
+        if e == "--" {
+            args.files.extend(raw[i ..].iter().cloned());
+            break
         }
 
-        if e.len() >= 2 && &e[0 .. 2] == "--" {
-            let e = &e[2 ..];
-            let contains = valid_args.iter().filter(
-                |a| a.name == e).count() != 0;
+        let (spec, inline) = if e.starts_with("--") {
+            let (name, inline) = match e.find('=') {
+                Some(p) => (&e[2 .. p], Some(e[p + 1 ..].to_string())),
+                None => (&e[2 ..], None),
+            };
 
-            if !contains {
-                panic!("Invalid argument of name '{}'!", e);
+            if name == "help" {
+                print_command_help(command);
+                process::exit(0)
             }
 
-            current_arg_name = e.to_string();
-            args.insert(current_arg_name.clone(), "".to_string());
+            match command.args.iter().find(|a| a.name == name) {
+                Some(spec) => (spec, inline),
+                None => usage_error(command, &format!("unknown option '--{}'", name)),
+            }
+        } else if e.starts_with('-') && e.len() > 1 {
+            let short = e[1 ..].chars().next().unwrap();
+            let rest = &e[1 + short.len_utf8() ..];
 
-        } else if e.len() >= 1 && &e[0 .. 1] == "-" {
-            let e = &e[1 ..];
-            let mut arg_name = "".to_string();
+            if short == 'h' && rest == "" {
+                print_command_help(command);
+                process::exit(0)
+            }
 
-            for arg in &valid_args {
-                if arg.short == Some(e.to_string()) {
-                    arg_name = arg.name.clone();
-                    break;
-                }
+            match command.args.iter().find(|a| a.short == Some(short)) {
+                Some(spec) => (spec, match rest {
+                    "" => None,
+                    rest => Some(rest.to_string()),
+                }),
+                None => usage_error(command, &format!("unknown option '-{}'", short)),
             }
+        } else {
+            args.files.push(e.clone());
+            continue
+        };
+
+        let value = match spec.arg {
+            Req::No => match inline {
+                Some(_) => usage_error(command, &format!("'--{}' doesn't take a value", spec.name)),
+                None => "".to_string(),
+            },
+            Req::Yes => match inline {
+                Some(val) => val,
+                None if i < raw.len() => {
+                    i += 1;
+                    raw[i - 1].clone()
+                },
+                None => usage_error(command, &format!("'--{}' needs a value", spec.name)),
+            },
+            // only takes the next argument if it is a number, so that a file
+            // following the option isn't mistaken for its value
+            Req::Maybe => match inline {
+                Some(val) => val,
+                None if i < raw.len() && usize::from_str(&raw[i]).is_ok() => {
+                    i += 1;
+                    raw[i - 1].clone()
+                },
+                None => "".to_string(),
+            },
+        };
+
+        match spec.name {
+            "file" => args.files.push(value),
+            "define" => args.defines.push(match value.find('=') {
+                Some(p) => (value[.. p].to_string(), value[p + 1 ..].to_string()),
+                None => (value, "1".to_string()),
+            }),
+            "include" => args.includes.push(value),
+            name => {
+                args.options.insert(name.to_string(), value);
+            },
+        }
+    }
 
-            if arg_name == "" {
-                panic!("Invalid argument of name {}!", e);
+    let (min, max) = command.files;
+    if args.files.len() < min {
+        usage_error(command, "no file was given");
+    }
+    if args.files.len() > max {
+        usage_error(command, &format!("expected at most {} file(s) but was given {}: {}",
+            max, args.files.len(), args.files.join(", ")));
+    }
+    args
+}
 
-            } else {
-                current_arg_name = arg_name;
-            }
+fn option<T: FromStr>(command: &Command, args: &Args, name: &str) -> Option<T> {
+    args.options.get(name).map(|val| match T::from_str(val) {
+        Ok(val) => val,
+        Err(_) => usage_error(command, &format!("'{}' is invalid for '--{}'", val, name)),
+    })
+}
 
-            args.insert(current_arg_name.clone(), "".to_string());
+fn new_garden(command: &Command, args: &Args) -> (Garden, Option<u64>) {
+    let seed: Option<u64> = option(command, args, "seed");
 
-        } else {
-            let arg = args.get_mut(&current_arg_name).expect("No argument!");
-            arg.push_str(e.as_ref());
+    (match seed {
+        Some(seed) => Garden::with_seed(seed),
+        None => Garden::new(),
+    }, seed)
+}
+
+fn new_tater(args: &Args) -> Tater {
+    let mut tater = Tater::new(args.options.contains_key("print-parsed"));
+    for &(ref name, ref value) in &args.defines {
+        tater.define(name, value);
+    }
+    tater.search_path.extend(args.includes.iter().map(PathBuf::from));
+    tater
+}
+
+fn run(command: &Command, args: &Args) {
+    let (mut garden, _) = new_garden(command, args);
+    let mut tater = new_tater(args);
+    tater.trace = args.options.contains_key("trace");
+
+    let bits: usize = match args.options.get("print-stack").map(|s| s.as_str()) {
+        Some("") => 64,
+        Some(_) => option(command, args, "print-stack").unwrap(),
+        None => 0,
+    };
+
+    if args.options.contains_key("print-stack") && bits == 0 {
+        usage_error(command, "'--print-stack' needs at least 1 bit");
+    }
+
+    let mut dump = Dump::new(bits);
+    dump.offsets  = args.options.contains_key("offsets");
+    dump.annotate = args.options.contains_key("annotate");
+
+    if let Some(format) = args.options.get("format") {
+        dump.format = match Format::from_str(format) {
+            Ok(format) => format,
+            Err(e) => usage_error(command, &format!("{}", e).trim_end_matches('!')),
+        };
+    }
+
+    if let Some(range) = args.options.get("range") {
+        let mut bounds = range.splitn(2, ':').map(|b| usize::from_str(b.trim()).ok());
+        match (bounds.next(), bounds.next()) {
+            (Some(Some(start)), end) => {
+                dump.start = start;
+                dump.end = end.and_then(|e| e);
+            },
+            _ => usage_error(command, &format!("'{}' is not a bit range, expected <start>:<end>", range)),
+        }
+    }
+
+    or_exit(tater.load_file(&args.files[0]));
+
+    match args.options.contains_key("debug") {
+        true  => or_exit(Debugger::new(&mut tater, &mut garden).run()),
+        false => or_exit(tater.run(&mut garden)),
+    }
+
+    if bits > 0 {
+        println!("{}", or_exit(dump.render_annotated(&garden, &tater).map_err(TaterError::from)));
+    }
+}
+
+fn check(_: &Command, args: &Args) {
+    let mut failed = false;
+
+    for file in &args.files {
+        let mut tater = new_tater(args);
+
+        match tater.load_file(file).and_then(|_| tater.link()) {
+            Ok(_) => println!("'{}' is fine, {} instruction(s).", file, tater.len()),
+            Err(e) => {
+                eprintln!("{}", e);
+                failed = true;
+            },
         }
     }
 
-    let print_parsed = args.contains_key("print_parsed");
-    let print_stack  = args.contains_key("print-stack");
+    if failed {
+        process::exit(1)
+    }
+}
+
+fn build(_: &Command, args: &Args) {
+    let file = &args.files[0];
+    let output = match args.options.get("output") {
+        Some(output) => output.clone(),
+        None => Path::new(file).with_extension("tatc").to_string_lossy().into_owned(),
+    };
+
+    if Path::new(&output) == Path::new(file) {
+        eprintln!("tater build: '{}' would overwrite the program itself!", output);
+        process::exit(2)
+    }
+
+    let mut tater = new_tater(args);
+    or_exit(tater.load_file(file));
+    let image = or_exit(tater.image());
+
+    or_exit(fs::write(&output, image).map_err(|e| TaterError::from(
+        ErrorType::Io(format!("'{}': {}", output, e)))));
 
-    let mut execute = false;
+    println!("'{}' built to '{}', {} instruction(s).", file, output, tater.len());
+}
+
+// Writes the program back as source, after strings and defines have been
+// expanded, with the index and origin of each instruction in a comment.
+fn disasm(_: &Command, args: &Args) {
+    let mut tater = new_tater(args);
+
+    or_exit(tater.load_file(&args.files[0]));
+    or_exit(tater.link());
 
-    let mut garden = Garden::new();
-    let mut tater  = Tater::new(print_parsed);
+    let mut labels: Vec<(&String, &usize)> = tater.labels.iter().collect();
+    labels.sort_by_key(|&(name, index)| (*index, name.clone()));
 
-    if args.contains_key("help") {
-        println!("{}",
-            "
-            [The Tater Language]
+    let mut labels = labels.into_iter().peekable();
+    let mut source = String::new();
 
-            Usage:
-                tater --file <filename>
+    for i in 0 .. tater.len() + 1 {
+        while labels.peek().map_or(false, |&(_, index)| *index == i) {
+            source.push_str(&format!("@{}\n", labels.next().unwrap().0));
+        }
 
-            Options:
-                --print-stack <bits> [prints stack as a sequence of bytes]
-                --print-parsed       [prints each line as it's are parsed]
-                --help               [display this message]
-            "
-        );
-    } else if args.contains_key("file") {
-        load_file(&mut tater, args.get("file").expect("Wtf?"));
-        execute = true;
-    } else {
-        println!("Type 'tater --help' for help on how to use Tater!")
+        if let (Some(ins), Some(span)) = (tater.instruction(i), tater.span(i)) {
+            source.push_str(&format!("{} ? {}: {}\n", ins.source(), i, span));
+        }
     }
 
-    if execute {
-        tater.run(&mut garden);
+    print!("{}", format_source(&source));
+}
+
+fn fmt(_: &Command, args: &Args) {
+    let check = args.options.contains_key("check");
+    let mut unformatted = false;
+
+    for file in &args.files {
+        let io_error = |e: std::io::Error| TaterError::from(
+            ErrorType::Io(format!("'{}': {}", file, e)));
+
+        let source = or_exit(fs::read_to_string(file).map_err(&io_error));
+        let formatted = format_source(&source);
 
-        if print_stack {
-            let bits = usize::from_str(
-                match args.get("print-stack").unwrap_or(&"64".to_string()).as_ref() {
-                    "" => "64",
-                    other => other,
-                }
-            ).expect("'print-stack' argument is invalid!");
+        if formatted == source {
+            continue
+        }
 
-            garden.print_bytes(bits);
+        match check {
+            true => {
+                println!("'{}' is not formatted.", file);
+                unformatted = true;
+            },
+            false => or_exit(fs::write(file, formatted).map_err(&io_error)),
         }
     }
+
+    if unformatted {
+        process::exit(1)
+    }
+}
+
+fn repl(command: &Command, args: &Args) {
+    let (mut garden, seed) = new_garden(command, args);
+    let mut tater = new_tater(args);
+
+    if let Some(file) = args.files.get(0) {
+        or_exit(tater.load_file(file));
+        or_exit(tater.run(&mut garden));
+    }
+
+    let defines = args.defines.clone();
+    Repl::new(tater, garden, seed, defines).run();
+}
+
+fn main() {
+    let commands = commands();
+    let mut raw: Vec<String> = env::args().skip(1).collect();
+
+    // '--repl' anywhere still starts the repl, as it did before commands
+    let is_command = raw.get(0).map_or(false, |first| commands.iter().any(|c| c.name == first));
+    let repl_flag = match raw.iter().position(|arg| arg == "--repl") {
+        Some(i) if !is_command => {
+            raw.remove(i);
+            true
+        },
+        _ => false,
+    };
+
+    let (name, rest) = match raw.get(0).map(|s| s.as_str()) {
+        _ if repl_flag => ("repl", &raw[..]),
+        None => ("repl", &raw[..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            match raw.get(1) {
+                Some(name) => match commands.iter().find(|c| c.name == name) {
+                    Some(command) => print_command_help(command),
+                    None => {
+                        eprintln!("tater: there is no command named '{}'!", name);
+                        process::exit(2)
+                    },
+                },
+                None => print_help(&commands),
+            }
+            return
+        },
+        Some(name) if commands.iter().any(|c| c.name == name) => (name, &raw[1 ..]),
+        // anything else is a file, or options, to run
+        Some(_) => ("run", &raw[..]),
+    };
+
+    let command = commands.iter().find(|c| c.name == name).unwrap();
+    let args = parse_args(command, rest);
+
+    match command.name {
+        "run" => run(command, &args),
+        "check" => check(command, &args),
+        "build" => build(command, &args),
+        "disasm" => disasm(command, &args),
+        "fmt" => fmt(command, &args),
+        "repl" => repl(command, &args),
+        _ => unreachable!(),
+    }
 }
@@ -0,0 +1,122 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use tater::{Tater, Garden, TaterError};
+
+const HELP: &'static str = "
+    Lines are parsed and run as soon as they are entered; labels, '#define',
+    '#macro' and '#require' carry over to later lines. A '#macro' runs
+    nothing until its '#end'.
+
+    Commands:
+        :stack [bits]  [prints stack as a sequence of bytes]
+        :reset         [forgets the program and clears the stack]
+        :help          [display this message]
+        :quit          [leave the repl]
+";
+
+pub struct Repl {
+    tater: Tater,
+    garden: Garden,
+    seed: Option<u64>,
+    defines: Vec<(String, String)>,
+    line: usize,
+}
+
+impl Repl {
+    // 'defines' are those from the command line, which ':reset' keeps along
+    // with the search path.
+    pub fn new(tater: Tater, garden: Garden, seed: Option<u64>, defines: Vec<(String, String)>) -> Repl {
+        Repl {
+            tater: tater,
+            garden: garden,
+            seed: seed,
+            defines: defines,
+            line: 0,
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("[The Tater Language] Type ':help' for help.");
+
+        loop {
+            print!("> ");
+            io::stdout().flush().ok();
+
+            let mut line = String::new();
+            let stdin = io::stdin();
+
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {},
+            }
+
+            let line = line.trim_end();
+
+            if line.trim_start().starts_with(':') {
+                match self.command(&line.trim_start()[1 ..]) {
+                    true  => continue,
+                    false => break,
+                }
+            }
+
+            match self.eval(line) {
+                Ok(_) => {},
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        println!("");
+    }
+
+    // Parses a line and runs whatever it added. A line that fails to parse
+    // or link is undone; after a runtime error, execution resumes at the
+    // end of the program.
+    fn eval(&mut self, line: &str) -> Result<(), TaterError> {
+        self.line += 1;
+
+        let point = self.tater.checkpoint();
+        let parsed = self.tater.parse_line(&line.to_string(), self.line, None)
+            .and_then(|_| self.tater.link());
+
+        if let Err(e) = parsed {
+            self.tater.restore(point);
+            return Err(e)
+        }
+
+        let result = self.tater.run(&mut self.garden);
+        if result.is_err() {
+            self.garden.instruction = self.tater.len();
+        }
+        result
+    }
+
+    // Returns false when the repl should stop.
+    fn command(&mut self, command: &str) -> bool {
+        let mut args = command.split_whitespace();
+
+        match args.next().unwrap_or("") {
+            "stack" => match usize::from_str(args.next().unwrap_or("64")) {
+                Ok(bits) if bits > 0 => self.garden.print_bytes(bits),
+                _ => eprintln!("'stack' argument is invalid!"),
+            },
+            "reset" => {
+                let mut tater = Tater::new(self.tater.print_parsed);
+                for &(ref name, ref value) in &self.defines {
+                    tater.define(name, value);
+                }
+                tater.search_path = self.tater.search_path.clone();
+                self.tater = tater;
+                self.garden = match self.seed {
+                    Some(seed) => Garden::with_seed(seed),
+                    None => Garden::new(),
+                };
+                self.line = 0;
+            },
+            "help" => println!("{}", HELP),
+            "quit" => return false,
+            other => eprintln!("Unknown command ':{}', type ':help' for help!", other),
+        }
+        true
+    }
+}
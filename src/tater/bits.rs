@@ -0,0 +1,576 @@
+extern crate gmp;
+
+use super::util::*;
+use super::error::ErrorType;
+
+use self::gmp::mpz::Mpz;
+
+use std::ops::Index;
+
+const WORD_BITS: usize = 64;
+
+// A growable sequence of bits packed into machine words. Bit 'i' lives at
+// bit 'i % 64' of word 'i / 64', and bits past 'len' are always zero.
+pub struct BitStack {
+    words: Vec<u64>,
+    len: usize,
+}
+
+// Read-only view of a range of a 'BitStack'.
+#[derive(Clone, Copy)]
+pub struct BitSlice<'a> {
+    stack: &'a BitStack,
+    start: usize,
+    len: usize,
+}
+
+pub struct BitIter<'a> {
+    slice: BitSlice<'a>,
+    pos: usize,
+}
+
+fn mask(bits: usize) -> u64 {
+    match bits >= WORD_BITS {
+        true  => !0,
+        false => (1 << bits) - 1,
+    }
+}
+
+// Rounds up without adding to 'bits', which may be close to usize::MAX.
+pub fn words_for(bits: usize) -> usize {
+    bits / WORD_BITS + (bits % WORD_BITS != 0) as usize
+}
+
+// Shifts the bits of 'words' towards the end by 'bits', keeping the first
+// 'len' words.
+pub fn shift_words_up(words: &mut Vec<u64>, bits: usize, len: usize) {
+    let (skip, carry) = (bits / WORD_BITS, bits % WORD_BITS);
+    let word = |words: &Vec<u64>, i: usize, back: usize| match i.checked_sub(back) {
+        Some(j) => words[j],
+        None => 0,
+    };
+
+    words.resize(len, 0);
+    for i in (0 .. len).rev() {
+        words[i] = match carry {
+            0 => word(words, i, skip),
+            _ => word(words, i, skip) << carry | word(words, i, skip + 1) >> (WORD_BITS - carry),
+        };
+    }
+}
+
+// Shifts the bits of 'words' towards the start by 'bits', filling in zeros.
+pub fn shift_words_down(words: &mut Vec<u64>, bits: usize) {
+    let (skip, carry) = (bits / WORD_BITS, bits % WORD_BITS);
+    let word = |words: &Vec<u64>, i: usize| words.get(i).cloned().unwrap_or(0);
+
+    if skip >= words.len() {
+        return words.iter_mut().for_each(|w| *w = 0)
+    }
+
+    for i in 0 .. words.len() {
+        words[i] = match carry {
+            0 => word(words, i + skip),
+            _ => word(words, i + skip) >> carry | word(words, i + skip + 1) << (WORD_BITS - carry),
+        };
+    }
+}
+
+impl BitStack {
+    pub fn new() -> BitStack {
+        BitStack {
+            words: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.words[i / WORD_BITS] & (1 << (i % WORD_BITS)) != 0
+    }
+
+    pub fn push(&mut self, bits: usize, value: bool) -> Result<(), ErrorType> {
+        let pos = self.len;
+
+        self.len = pos.checked_add(bits).ok_or(ErrorType::OutOfBounds {
+            start: pos,
+            end: usize::max_value(),
+            len: pos,
+        })?;
+
+        let words = words_for(self.len);
+        if self.words.try_reserve(words - self.words.len()).is_err() {
+            self.len = pos;
+            return Err(ErrorType::Generic(format!("there isn't the memory to push {} bits", bits)))
+        }
+        self.words.resize(words, 0);
+
+        if value {
+            self.fill(pos, bits, true);
+        }
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return
+        }
+
+        self.len = len;
+        self.words.truncate(words_for(len));
+
+        if len % WORD_BITS != 0 {
+            let last = self.words.len() - 1;
+            self.words[last] &= mask(len % WORD_BITS);
+        }
+    }
+
+    // Reads up to 64 bits starting at 'pos' as the low bits of a word.
+    pub fn read(&self, pos: usize, bits: usize) -> u64 {
+        if bits == 0 {
+            return 0
+        }
+
+        let word = pos / WORD_BITS;
+        let offset = pos % WORD_BITS;
+
+        let mut val = self.words[word] >> offset;
+        if offset != 0 && offset + bits > WORD_BITS {
+            val |= self.words[word + 1] << (WORD_BITS - offset);
+        }
+        val & mask(bits)
+    }
+
+    // Writes the low 'bits' (up to 64) bits of 'val' starting at 'pos'.
+    pub fn write(&mut self, pos: usize, bits: usize, val: u64) {
+        if bits == 0 {
+            return
+        }
+
+        let val = val & mask(bits);
+        let word = pos / WORD_BITS;
+        let offset = pos % WORD_BITS;
+
+        self.words[word] = (self.words[word] & !(mask(bits) << offset)) | (val << offset);
+
+        if offset + bits > WORD_BITS {
+            let spill = offset + bits - WORD_BITS;
+            self.words[word + 1] = (self.words[word + 1] & !mask(spill)) | (val >> (WORD_BITS - offset));
+        }
+    }
+
+    pub fn fill(&mut self, pos: usize, len: usize, value: bool) {
+        let val = match value {
+            true  => !0,
+            false => 0,
+        };
+
+        let mut i = 0;
+        while i < len {
+            let bits = WORD_BITS.min(len - i);
+            self.write(pos + i, bits, val);
+            i += bits;
+        }
+    }
+
+    // Copies 'len' bits from 'from' to 'to'; the ranges may overlap.
+    pub fn copy(&mut self, from: usize, to: usize, len: usize) {
+        if to <= from || to >= from + len {
+            let mut i = 0;
+            while i < len {
+                let bits = WORD_BITS.min(len - i);
+                let val = self.read(from + i, bits);
+                self.write(to + i, bits, val);
+                i += bits;
+            }
+        } else {
+            let mut i = len;
+            while i > 0 {
+                let bits = WORD_BITS.min(i);
+                i -= bits;
+                let val = self.read(from + i, bits);
+                self.write(to + i, bits, val);
+            }
+        }
+    }
+
+    // Appends the 'len' bits at 'pos' to 'out' as 64 bit words.
+    pub fn read_words(&self, pos: usize, len: usize, out: &mut Vec<u64>) {
+        let mut i = 0;
+        while i < len {
+            let bits = WORD_BITS.min(len - i);
+            out.push(self.read(pos + i, bits));
+            i += bits;
+        }
+    }
+
+    // Writes 'words' to the 'len' bits at 'pos', zero extending or truncating them.
+    pub fn write_words(&mut self, pos: usize, len: usize, words: &[u64]) {
+        let mut i = 0;
+        let mut n = 0;
+        while i < len {
+            let bits = WORD_BITS.min(len - i);
+            self.write(pos + i, bits, words.get(n).cloned().unwrap_or(0));
+            i += bits;
+            n += 1;
+        }
+    }
+
+    // Writes 'bits' to the 'len' bits at 'pos', zero extending or truncating it.
+    pub fn write_bits(&mut self, pos: usize, bits: &[bool], len: usize) {
+        let mut i = 0;
+        while i < len {
+            let n = WORD_BITS.min(len - i);
+            let mut val: u64 = 0;
+
+            for j in 0 .. n.min(bits.len().saturating_sub(i)) {
+                if bits[i + j] {
+                    val |= 1 << j;
+                }
+            }
+
+            self.write(pos + i, n, val);
+            i += n;
+        }
+    }
+
+    // Compares two ranges as unsigned numbers of possibly different widths.
+    pub fn equal(&self, a: usize, a_len: usize, b: usize, b_len: usize) -> bool {
+        let len = a_len.max(b_len);
+
+        let mut i = 0;
+        while i < len {
+            let bits = WORD_BITS.min(len - i);
+            let a_val = self.read(a + i.min(a_len), bits.min(a_len.saturating_sub(i)));
+            let b_val = self.read(b + i.min(b_len), bits.min(b_len.saturating_sub(i)));
+
+            if a_val != b_val {
+                return false
+            }
+            i += bits;
+        }
+        true
+    }
+
+    // Sets 'out' to the 'len' bits at 'pos' read as an unsigned number,
+    // importing whole words straight from the stack.
+    pub fn load_bignum(&self, pos: usize, len: usize, out: &mut Mpz) {
+        if len == 0 {
+            out.set(&Mpz::zero());
+            return
+        }
+
+        let first = pos / WORD_BITS;
+        let last = (pos + len - 1) / WORD_BITS;
+
+        import_words(out, &self.words[first ..= last]);
+        shift_right(out, pos % WORD_BITS);
+        truncate_bits(out, len);
+    }
+
+    // Writes the low 'len' bits of the non-negative 'num' at 'pos' a limb at
+    // a time, zero extending it.
+    pub fn store_bignum(&mut self, num: &Mpz, pos: usize, len: usize) {
+        let mut i = 0;
+        let mut n = 0;
+        while i < len {
+            let bits = LIMB_BITS.min(len - i);
+            self.write(pos + i, bits, limb(num, n) as u64);
+            i += bits;
+            n += 1;
+        }
+    }
+
+    pub fn slice(&self, start: usize, end: usize) -> BitSlice<'_> {
+        BitSlice {
+            stack: self,
+            start: start,
+            len: end - start,
+        }
+    }
+}
+
+impl<'a> BitSlice<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        assert!(i < self.len, "bit {} out of range for slice of {} bit(s)", i, self.len);
+        self.stack.get(self.start + i)
+    }
+
+    // Reads up to 64 bits starting at 'pos' within the slice.
+    pub fn read(&self, pos: usize, bits: usize) -> u64 {
+        assert!(pos + bits <= self.len, "bits {}->{} out of range for slice of {} bit(s)",
+            pos, pos + bits, self.len);
+        self.stack.read(self.start + pos, bits)
+    }
+
+    pub fn iter(&self) -> BitIter<'a> {
+        BitIter {
+            slice: *self,
+            pos: 0,
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<bool> {
+        self.iter().collect()
+    }
+
+    pub fn to_bignum(&self) -> Mpz {
+        let mut ret = Mpz::new();
+        self.stack.load_bignum(self.start, self.len, &mut ret);
+        ret
+    }
+}
+
+impl<'a> Index<usize> for BitSlice<'a> {
+    type Output = bool;
+
+    fn index(&self, i: usize) -> &bool {
+        match self.get(i) {
+            true  => &true,
+            false => &false,
+        }
+    }
+}
+
+impl<'a> Iterator for BitIter<'a> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<bool> {
+        if self.pos >= self.slice.len {
+            return None
+        }
+
+        self.pos += 1;
+        Some(self.slice.get(self.pos - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSETS: [usize; 9] = [0, 1, 3, 61, 63, 64, 65, 127, 130];
+    const WIDTHS: [usize; 4] = [1, 63, 64, 65];
+
+    // A 'BitStack' alongside the plain bits it should hold.
+    struct Model {
+        stack: BitStack,
+        bits: Vec<bool>,
+        state: u64,
+    }
+
+    impl Model {
+        fn new(len: usize) -> Model {
+            let mut model = Model {
+                stack: BitStack::new(),
+                bits: vec![],
+                state: 1,
+            };
+            model.stack.push(len, false).unwrap();
+            model.bits.resize(len, false);
+
+            for pos in 0 .. len {
+                let val = model.next() & 1 != 0;
+                model.stack.write(pos, 1, val as u64);
+                model.bits[pos] = val;
+            }
+            model.check();
+            model
+        }
+
+        fn next(&mut self) -> u64 {
+            self.state = self.state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            self.state ^ (self.state >> 29)
+        }
+
+        fn check(&self) {
+            assert_eq!(self.stack.len(), self.bits.len());
+            assert_eq!(self.stack.slice(0, self.stack.len()).to_vec(), self.bits);
+
+            // bits past the end are kept zero
+            assert_eq!(self.stack.words.len(), words_for(self.bits.len()));
+            if let Some(last) = self.stack.words.last() {
+                assert_eq!(last & !mask(self.bits.len() - (self.stack.words.len() - 1) * WORD_BITS), 0);
+            }
+        }
+
+        fn value(&self, pos: usize, len: usize) -> u64 {
+            let mut ret = 0;
+            for i in 0 .. len {
+                if self.bits[pos + i] {
+                    ret |= 1 << i;
+                }
+            }
+            ret
+        }
+    }
+
+    #[test]
+    fn read_and_write() {
+        let mut model = Model::new(400);
+
+        for &pos in &OFFSETS {
+            for &bits in WIDTHS.iter().filter(|&&bits| bits <= WORD_BITS) {
+                assert_eq!(model.stack.read(pos, bits), model.value(pos, bits), "read {} at {}", bits, pos);
+
+                let val = model.next();
+                model.stack.write(pos, bits, val);
+                for i in 0 .. bits {
+                    model.bits[pos + i] = val & (1 << i) != 0;
+                }
+                model.check();
+                assert_eq!(model.stack.slice(pos, pos + bits).read(0, bits), val & mask(bits));
+            }
+        }
+    }
+
+    #[test]
+    fn fill() {
+        let mut model = Model::new(400);
+
+        for &pos in &OFFSETS {
+            for &len in &WIDTHS {
+                for &value in &[true, false] {
+                    model.stack.fill(pos, len, value);
+                    for i in 0 .. len {
+                        model.bits[pos + i] = value;
+                    }
+                    model.check();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn copy() {
+        for &from in &OFFSETS {
+            for &to in &OFFSETS {
+                for &len in &WIDTHS {
+                    // overlapping whenever 'from' and 'to' are less than 'len' apart
+                    let mut model = Model::new(400);
+                    let expected: Vec<bool> = model.bits[from .. from + len].to_vec();
+
+                    model.stack.copy(from, to, len);
+                    model.bits[to .. to + len].copy_from_slice(&expected);
+                    model.check();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equal() {
+        let mut model = Model::new(400);
+
+        for &a in &OFFSETS {
+            for &b in &OFFSETS {
+                for &a_len in &WIDTHS {
+                    for &b_len in &WIDTHS {
+                        let val = |pos: usize, len: usize| model.bits[pos .. pos + len].to_vec();
+                        let (mut x, mut y) = (val(a, a_len), val(b, b_len));
+                        x.resize(a_len.max(b_len), false);
+                        y.resize(a_len.max(b_len), false);
+
+                        assert_eq!(model.stack.equal(a, a_len, b, b_len), x == y,
+                            "{} at {} and {} at {}", a_len, a, b_len, b);
+                    }
+                }
+            }
+        }
+
+        // equal numbers of different widths
+        model.stack.fill(200, 65, false);
+        model.stack.write(300, 64, 5);
+        model.stack.write(200, 3, 5);
+        assert!(model.stack.equal(200, 65, 300, 3));
+        assert!(!model.stack.equal(200, 65, 300, 1));
+    }
+
+    #[test]
+    fn words_and_bignums() {
+        let mut model = Model::new(400);
+
+        for &pos in &OFFSETS {
+            for &len in &WIDTHS {
+                let mut words = vec![];
+                model.stack.read_words(pos, len, &mut words);
+                let expected: Vec<u64> = (0 .. words_for(len))
+                    .map(|n| model.value(pos + n * WORD_BITS, WORD_BITS.min(len - n * WORD_BITS))).collect();
+                assert_eq!(words, expected);
+
+                let num = model.stack.slice(pos, pos + len).to_bignum();
+                assert_eq!(num, boolvec_to_bignum(&model.bits[pos .. pos + len]));
+
+                let words = vec![model.next(), model.next()];
+                model.stack.write_words(pos, len, &words);
+                for i in 0 .. len {
+                    model.bits[pos + i] = i < 128 && words[i / 64] & (1 << (i % 64)) != 0;
+                }
+                model.check();
+
+                let bits: Vec<bool> = (0 .. len / 2).map(|i| i % 3 == 0).collect();
+                model.stack.write_bits(pos, &bits, len);
+                for i in 0 .. len {
+                    model.bits[pos + i] = i < bits.len() && bits[i];
+                }
+                model.check();
+
+                let mut num = Mpz::zero();
+                import_words(&mut num, &[model.next(), model.next()]);
+                model.stack.store_bignum(&num, pos, len);
+                for i in 0 .. len {
+                    model.bits[pos + i] = num.tstbit(i);
+                }
+                model.check();
+            }
+        }
+    }
+
+    #[test]
+    fn push_past_the_end_of_memory() {
+        assert_eq!(words_for(usize::max_value()), usize::max_value() / WORD_BITS + 1);
+
+        let mut model = Model::new(8);
+        assert!(model.stack.push(usize::max_value(), false).is_err());
+        assert!(model.stack.push(usize::max_value() - 8, false).is_err());
+        model.check();
+    }
+
+    #[test]
+    fn push_and_truncate() {
+        let mut model = Model::new(0);
+
+        for &len in &WIDTHS {
+            for &value in &[true, false] {
+                model.stack.push(len, value).unwrap();
+                let new_len = model.bits.len() + len;
+                model.bits.resize(new_len, value);
+                model.check();
+            }
+        }
+
+        for &len in &[300, 200, 129, 128, 65, 64, 63, 1, 0] {
+            model.stack.truncate(len);
+            model.bits.truncate(len);
+            model.check();
+
+            // nothing truncated away comes back when the stack grows again
+            model.stack.push(3, false).unwrap();
+            model.bits.resize(len + 3, false);
+            model.check();
+            model.stack.truncate(len);
+            model.bits.truncate(len);
+        }
+    }
+}
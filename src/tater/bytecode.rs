@@ -0,0 +1,535 @@
+extern crate gmp;
+
+use super::value::Value;
+use super::garden::Garden;
+use super::tater::Tater;
+use super::bits::{words_for, shift_words_up, shift_words_down};
+use super::error::*;
+use super::util::*;
+
+use self::gmp::mpz::Mpz;
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::mem;
+
+// A number known when the program is linked, with the bits it stands for
+// and how many of them there are.
+#[derive(PartialEq)]
+pub struct Constant {
+    pub num: Mpz,
+    pub words: Vec<u64>,
+    pub size: usize,
+}
+
+// Operands are resolved as far as possible while linking: literals become
+// indices into 'Program::constants', pointers with literal bounds become
+// plain ranges, and anything else is kept as a 'Value' to evaluate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Const(usize),
+    Ptr {
+        pos: usize,
+        len: usize,
+    },
+    RevPtr {
+        pos: usize,
+        len: usize,
+    },
+    Value(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arith {
+    Add, Sub, Mul, Div, Mod,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Logic {
+    And, Or, Xor,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compare {
+    Equal, Less, Greater,
+}
+
+// One instruction of the lowered program. Two operand forms such as
+// 'add to; a' are lowered with 'left' set to 'to'.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Nop,
+
+    Put {
+        size: Operand,
+        value: Option<Operand>,
+    },
+    Pop(Operand),
+    Move {
+        to: Operand,
+        from: Operand,
+    },
+
+    Arith {
+        op: Arith,
+        to: Operand,
+        left: Operand,
+        right: Operand,
+    },
+    Random {
+        to: Operand,
+        bound: Option<Operand>,
+    },
+
+    Logic {
+        op: Logic,
+        to: Operand,
+        left: Operand,
+        right: Operand,
+    },
+    Not {
+        to: Operand,
+        from: Operand,
+    },
+    Shift {
+        towards_end: bool,
+        to: Operand,
+        from: Operand,
+        amount: Operand,
+    },
+
+    Compare {
+        op: Compare,
+        a: Operand,
+        b: Operand,
+    },
+    Zero(Operand),
+
+    Call(usize),
+    Return,
+    Jump(usize),
+    JumpIf(usize),
+    JumpUnless(usize),
+
+    Extern {
+        call: usize,
+        value: usize,
+    },
+}
+
+// An op together with the 'if'/'unless' prefix it was written with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Code {
+    pub when: Option<bool>,
+    pub op: Op,
+}
+
+struct Scratch {
+    left: Mpz,
+    right: Mpz,
+    left_words: Vec<u64>,
+    right_words: Vec<u64>,
+}
+
+// A linked program: flat code plus the pools its operands point into.
+pub struct Program {
+    pub code: Vec<Code>,
+    pub constants: Vec<Constant>,
+    pub values: Vec<Value>,
+    pub externs: Vec<String>,
+    scratch: RefCell<Scratch>,
+}
+
+impl From<Op> for Code {
+    fn from(op: Op) -> Code {
+        Code {
+            when: None,
+            op: op,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Program {
+    pub fn new() -> Program {
+        Program {
+            code: Vec::new(),
+            constants: Vec::new(),
+            values: Vec::new(),
+            externs: Vec::new(),
+            scratch: RefCell::new(Scratch {
+                left: Mpz::new(),
+                right: Mpz::new(),
+                left_words: Vec::new(),
+                right_words: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn constant(&mut self, value: &Value) -> usize {
+        let constant = match *value {
+            Value::Boolvec(ref vec) => Constant {
+                num: boolvec_to_bignum(vec),
+                words: boolvec_to_words(vec),
+                size: vec.len(),
+            },
+            Value::Bignum(ref num) => Constant {
+                num: num.clone(),
+                words: boolvec_to_words(&bignum_to_boolvec(num)),
+                size: num.bit_length(),
+            },
+            _ => return self.value(value),
+        };
+
+        self.constants.push(constant);
+        self.constants.len() - 1
+    }
+
+    pub fn value(&mut self, value: &Value) -> usize {
+        self.values.push(value.clone());
+        self.values.len() - 1
+    }
+
+    pub fn operand(&mut self, value: &Value) -> Operand {
+        match *value {
+            Value::Boolvec(_) | Value::Bignum(_) => Operand::Const(self.constant(value)),
+            Value::Pointer {ref pos, ref len, rev} => match (&**pos, &**len) {
+                // ones too large are left to fail when they're used
+                (&Value::Bignum(ref pos), &Value::Bignum(ref len)) => match (bignum_to_usize(pos), bignum_to_usize(len)) {
+                    (Ok(pos), Ok(len)) => match rev {
+                        true  => Operand::RevPtr { pos: pos, len: len },
+                        false => Operand::Ptr { pos: pos, len: len },
+                    },
+                    _ => Operand::Value(self.value(value)),
+                },
+                _ => Operand::Value(self.value(value)),
+            },
+            _ => Operand::Value(self.value(value)),
+        }
+    }
+
+    pub fn label(&self, name: &str, tater: &Tater) -> Result<usize, TaterError> {
+        match tater.labels.get(name) {
+            Some(index) => Ok(*index),
+            None => Err(TaterError::from(ErrorType::NonExistent {
+                type_name: "label".to_string(),
+                value: name.to_string(),
+            })),
+        }
+    }
+
+    pub fn external(&mut self, name: &str, tater: &Tater) -> Result<usize, TaterError> {
+        if !tater.ext_calls.contains_key(name) {
+            return Err(TaterError::from(ErrorType::NonExistent {
+                type_name: "external call".to_string(),
+                value: name.to_string(),
+            }))
+        }
+
+        self.externs.push(name.to_string());
+        Ok(self.externs.len() - 1)
+    }
+
+    pub fn is_ptr(&self, op: &Operand) -> bool {
+        match *op {
+            Operand::Const(_) => false,
+            Operand::Ptr {..} | Operand::RevPtr {..} => true,
+            Operand::Value(i) => self.values[i].is_ptr(),
+        }
+    }
+
+    pub fn position(&self, op: &Operand, garden: &Garden) -> Result<usize, ErrorType> {
+        match *op {
+            Operand::Ptr {pos, ..} => Ok(pos),
+            Operand::RevPtr {pos, ..} => match garden.stack_len().checked_sub(pos) {
+                Some(pos) => Ok(pos),
+                None => Err(ErrorType::OutOfBounds {
+                    start: pos,
+                    end: pos,
+                    len: garden.stack_len(),
+                }),
+            },
+            Operand::Value(i) => self.values[i].get_ptr_position(garden),
+            Operand::Const(i) => Err(ErrorType::InvalidPointer(
+                Value::Bignum(self.constants[i].num.clone()))),
+        }
+    }
+
+    pub fn size(&self, op: &Operand, garden: &Garden) -> Result<usize, ErrorType> {
+        match *op {
+            Operand::Ptr {len, ..} | Operand::RevPtr {len, ..} => Ok(len),
+            Operand::Const(i) => Ok(self.constants[i].size),
+            Operand::Value(i) => self.values[i].get_size(garden),
+        }
+    }
+
+    pub fn get_usize(&self, op: &Operand, garden: &Garden) -> Result<usize, ErrorType> {
+        match *op {
+            Operand::Const(i) => bignum_to_usize(&self.constants[i].num),
+            Operand::Value(i) => self.values[i].get_usize(garden),
+            _ => {
+                let mut num = Mpz::new();
+                self.load(op, garden, &mut num)?;
+                bignum_to_usize(&num)
+            },
+        }
+    }
+
+    // Sets 'out' to the operand read as a number, reusing its memory.
+    pub fn load(&self, op: &Operand, garden: &Garden, out: &mut Mpz) -> Result<(), ErrorType> {
+        match *op {
+            Operand::Const(i) => out.set(&self.constants[i].num),
+            Operand::Ptr {pos, len} => garden.load_bignum(pos, len, out)?,
+            Operand::RevPtr {len, ..} => garden.load_bignum(self.position(op, garden)?, len, out)?,
+            Operand::Value(i) => self.values[i].load_bignum(garden, out)?,
+        }
+        Ok(())
+    }
+
+    // Sets 'out' to the bits of the operand as 64 bit words.
+    pub fn load_words(&self, op: &Operand, garden: &Garden, out: &mut Vec<u64>) -> Result<(), ErrorType> {
+        match *op {
+            Operand::Const(i) => {
+                out.clear();
+                out.extend_from_slice(&self.constants[i].words);
+            },
+            Operand::Value(i) if !self.values[i].is_ptr() => {
+                *out = boolvec_to_words(&self.values[i].get_boolvec(garden)?);
+            },
+            _ => garden.read_words(self.position(op, garden)?, self.size(op, garden)?, out)?,
+        }
+        Ok(())
+    }
+
+    // Runs the op at 'index'; 'Garden::instruction' should already point
+    // past it.
+    pub fn exec(&self, index: usize, garden: &mut Garden, tater: &Tater) -> Result<(), TaterError> {
+        let code = &self.code[index];
+
+        match code.when {
+            Some(when) if when != garden.validity => return Ok(()),
+            _ => {},
+        }
+
+        match code.op {
+            Op::Nop => {},
+
+            Op::Put {ref size, ref value} => {
+                let size = self.get_usize(size, garden)?;
+                let pos = garden.stack_len();
+
+                garden.push(size, false)?;
+
+                if let Some(ref value) = *value {
+                    let num = &mut self.scratch.borrow_mut().left;
+                    self.load(value, garden, num)?;
+                    garden.set_bits_bignum(num, pos, size)?;
+                }
+            },
+            Op::Pop(ref size) => {
+                let size = self.get_usize(size, garden)?;
+                garden.pop(size)?;
+            },
+            Op::Move {ref to, ref from} => {
+                let size = self.size(to, garden)?;
+                let from_size = self.size(from, garden)?;
+
+                if from_size > size {
+                    return Err(TaterError::from(ErrorType::Overflow {
+                        size: from_size,
+                        target: size,
+                    }));
+                }
+
+                let pos = self.position(to, garden)?;
+
+                if self.is_ptr(from) {
+                    let from = self.position(from, garden)?;
+                    garden.copy_bits(from, from_size, pos, size)?;
+                } else {
+                    let num = &mut self.scratch.borrow_mut().left;
+                    self.load(from, garden, num)?;
+                    garden.set_bits_bignum(num, pos, size)?;
+                }
+            },
+
+            Op::Arith {op, ref to, ref left, ref right} => {
+                let mut scratch = self.scratch.borrow_mut();
+                let Scratch {left: ref mut l, right: ref mut r, ..} = *scratch;
+
+                self.load(left, garden, l)?;
+                self.load(right, garden, r)?;
+
+                let divides = match op {
+                    Arith::Div | Arith::Mod => true,
+                    _ => false,
+                };
+
+                if divides && r.is_zero() {
+                    return Err(TaterError::from(ErrorType::DivisionByZero))
+                }
+
+                let pos  = self.position(to, garden)?;
+                let size = self.size(to, garden)?;
+
+                let l_val = mem::replace(l, Mpz::new());
+                let mut result = match op {
+                    Arith::Add => l_val + &*r,
+                    Arith::Sub => l_val - &*r,
+                    Arith::Mul => l_val * &*r,
+                    Arith::Div => l_val.div_floor(r),
+                    Arith::Mod => l_val.mod_floor(r),
+                };
+                truncate_bits(&mut result, size);
+
+                garden.set_bits_bignum(&result, pos, size)?;
+                *l = result;
+            },
+            Op::Random {ref to, ref bound} => {
+                let pos  = self.position(to, garden)?;
+                let size = self.size(to, garden)?;
+
+                let num = match *bound {
+                    Some(ref bound) => {
+                        let b = &mut self.scratch.borrow_mut().right;
+                        self.load(bound, garden, b)?;
+
+                        if b.is_zero() {
+                            return Err(TaterError::from(ErrorType::InvalidValue(
+                                "random bound must be above zero".to_string())))
+                        }
+
+                        garden.rand_state.urandom(b)
+                    },
+                    None => garden.rand_state.urandom_2exp(size as u64),
+                };
+
+                garden.set_bits_bignum(&num, pos, size)?;
+            },
+
+            Op::Logic {op, ref to, ref left, ref right} => {
+                let mut scratch = self.scratch.borrow_mut();
+                let Scratch {left_words: ref mut l, right_words: ref mut r, ..} = *scratch;
+
+                self.load_words(left, garden, l)?;
+                self.load_words(right, garden, r)?;
+
+                let pos  = self.position(to, garden)?;
+                let size = self.size(to, garden)?;
+
+                l.resize(words_for(size), 0);
+                for (i, word) in l.iter_mut().enumerate() {
+                    let other = r.get(i).cloned().unwrap_or(0);
+                    *word = match op {
+                        Logic::And => *word & other,
+                        Logic::Or  => *word | other,
+                        Logic::Xor => *word ^ other,
+                    };
+                }
+
+                garden.write_words(pos, size, l)?;
+            },
+            Op::Not {ref to, ref from} => {
+                let l = &mut self.scratch.borrow_mut().left_words;
+                self.load_words(from, garden, l)?;
+
+                let pos  = self.position(to, garden)?;
+                let size = self.size(to, garden)?;
+
+                l.resize(words_for(size), 0);
+                for word in l.iter_mut() {
+                    *word = !*word;
+                }
+
+                garden.write_words(pos, size, l)?;
+            },
+            Op::Shift {towards_end, ref to, ref from, ref amount} => {
+                let words = &mut self.scratch.borrow_mut().left_words;
+
+                self.load_words(from, garden, words)?;
+                let amount = self.get_usize(amount, garden)?;
+
+                let pos  = self.position(to, garden)?;
+                let size = self.size(to, garden)?;
+
+                // bits shifted down can still come from past the width of to
+                if towards_end && amount >= size {
+                    garden.fill_bits(pos, size, false)?;
+                    return Ok(())
+                }
+
+                match towards_end {
+                    true  => shift_words_up(words, amount, words_for(size)),
+                    false => shift_words_down(words, amount),
+                }
+
+                words.resize(words_for(size), 0);
+                if size % 64 != 0 {
+                    let last = words.len() - 1;
+                    words[last] &= (1 << (size % 64)) - 1;
+                }
+
+                garden.write_words(pos, size, words)?;
+            },
+
+            Op::Compare {op: Compare::Equal, ref a, ref b} if self.is_ptr(a) && self.is_ptr(b) => {
+                garden.validity = garden.equal_bits(
+                    self.position(a, garden)?, self.size(a, garden)?,
+                    self.position(b, garden)?, self.size(b, garden)?)?;
+            },
+            Op::Compare {op, ref a, ref b} => {
+                let mut scratch = self.scratch.borrow_mut();
+                let Scratch {left: ref mut l, right: ref mut r, ..} = *scratch;
+
+                self.load(a, garden, l)?;
+                self.load(b, garden, r)?;
+
+                garden.validity = l.cmp(&r) == match op {
+                    Compare::Equal => Ordering::Equal,
+                    Compare::Less => Ordering::Less,
+                    Compare::Greater => Ordering::Greater,
+                };
+            },
+            Op::Zero(ref a) => {
+                garden.validity = match self.is_ptr(a) {
+                    true  => {
+                        let pos = self.position(a, garden)?;
+                        let size = self.size(a, garden)?;
+                        garden.equal_bits(pos, size, pos, 0)?
+                    },
+                    false => {
+                        let num = &mut self.scratch.borrow_mut().left;
+                        self.load(a, garden, num)?;
+                        num.is_zero()
+                    },
+                };
+            },
+
+            Op::Call(target) => garden.call(target),
+            Op::Return => garden.ret()?,
+            Op::Jump(target) => garden.goto(target),
+            Op::JumpIf(target) => if garden.validity {
+                garden.goto(target);
+            },
+            Op::JumpUnless(target) => if !garden.validity {
+                garden.goto(target);
+            },
+
+            Op::Extern {call, value} => {
+                let name = &self.externs[call];
+                match tater.ext_calls.get(name) {
+                    Some(f) => f(&self.values[value], garden, tater)?,
+                    None => return Err(TaterError::from(ErrorType::NonExistent {
+                        type_name: "external call".to_string(),
+                        value: name.clone(),
+                    })),
+                }
+            },
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+extern crate gmp;
+
+use super::value::Value;
+use super::garden::{Garden, Allocation};
+use super::tater::Tater;
+use super::error::*;
+use super::util::*;
+
+use self::gmp::mpz::Mpz;
+
+use std::str::FromStr;
+
+// How each chunk of a stack dump is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Unsigned,
+    Signed,
+    Hex,
+    Binary,
+    Ascii,
+}
+
+// What part of the stack to dump, and how:
+//     bits      size of each chunk, the last one may be shorter
+//     start/end range of bits to dump, 'end' defaults to the stack length
+//     offsets   one chunk per line, after the bit it starts at
+//     annotate  one allocation per line, named after the '#define' that
+//               points to it or the 'put' that pushed it
+#[derive(Clone, Debug)]
+pub struct Dump {
+    pub bits: usize,
+    pub format: Format,
+    pub start: usize,
+    pub end: Option<usize>,
+    pub offsets: bool,
+    pub annotate: bool,
+}
+
+impl FromStr for Format {
+    type Err = ErrorType;
+
+    fn from_str(s: &str) -> Result<Format, ErrorType> {
+        match s {
+            "unsigned" | "u" => Ok(Format::Unsigned),
+            "signed" | "s" => Ok(Format::Signed),
+            "hex" | "x" => Ok(Format::Hex),
+            "binary" | "b" => Ok(Format::Binary),
+            "ascii" | "a" => Ok(Format::Ascii),
+            other => Err(ErrorType::InvalidValue(format!(
+                "'{}' is not a stack format, expected unsigned, signed, hex, binary or ascii", other))),
+        }
+    }
+}
+
+impl Format {
+    // Writes the 'len' bits at 'pos'. Binary is written like a boolvec, and
+    // ascii reads every 8 bits the way strings are stored.
+    pub fn render(&self, garden: &Garden, pos: usize, len: usize) -> Result<String, ErrorType> {
+        let mut num = Mpz::new();
+        garden.load_bignum(pos, len, &mut num)?;
+
+        Ok(match *self {
+            Format::Unsigned => format!("{}", num),
+            Format::Signed => {
+                if len > 0 && num.tstbit(len - 1) {
+                    num = num - (Mpz::one() << len);
+                }
+                format!("{}", num)
+            },
+            Format::Hex => format!("0x{:0>1$}", num.to_str_radix(16), (len + 3) / 4),
+            Format::Binary => {
+                let bits = garden.slice(pos, pos + len)?;
+                "b".to_string() + &bits.iter().map(|b| if b { '1' } else { '0' }).collect::<String>()
+            },
+            Format::Ascii => {
+                let bits = garden.slice(pos, pos + len)?.to_vec();
+                bits.chunks(8).map(|byte| match boolvec_to_u8(byte) {
+                    c @ 0x20 ..= 0x7e => c as char,
+                    _ => '.',
+                }).collect()
+            },
+        })
+    }
+}
+
+impl Dump {
+    pub fn new(bits: usize) -> Dump {
+        Dump {
+            bits: bits,
+            format: Format::Unsigned,
+            start: 0,
+            end: None,
+            offsets: false,
+            annotate: false,
+        }
+    }
+
+    fn range(&self, garden: &Garden) -> Result<(usize, usize), ErrorType> {
+        let end = self.end.unwrap_or(garden.stack_len());
+        garden.slice(self.start, end)?;
+        Ok((self.start, end))
+    }
+
+    fn chunks(&self, garden: &Garden, start: usize, end: usize) -> Result<Vec<(usize, String)>, ErrorType> {
+        let bits = self.bits.max(1);
+        let mut ret = vec![];
+
+        let mut pos = start;
+        while pos < end {
+            let len = bits.min(end - pos);
+            ret.push((pos, self.format.render(garden, pos, len)?));
+            pos += len;
+        }
+        Ok(ret)
+    }
+
+    fn join(&self, chunks: &[(usize, String)], separator: &str) -> String {
+        let values: Vec<&str> = chunks.iter().map(|c| c.1.as_str()).collect();
+        match self.format {
+            Format::Ascii => values.concat(),
+            _ => values.join(separator),
+        }
+    }
+
+    // The dump without annotations, which need the program and so are
+    // left to 'render_annotated'.
+    pub fn render(&self, garden: &Garden) -> Result<String, ErrorType> {
+        let (start, end) = self.range(garden)?;
+        let chunks = self.chunks(garden, start, end)?;
+
+        if self.offsets {
+            let lines: Vec<String> = chunks.iter().map(
+                |&(pos, ref val)| format!("{:>8}: {}", pos, val)).collect();
+            return Ok(lines.join("\n"))
+        }
+
+        Ok(match self.format {
+            Format::Ascii => self.join(&chunks, ""),
+            _ => chunks.iter().map(|c| format!("{}, ", c.1)).collect(),
+        })
+    }
+
+    pub fn render_annotated(&self, garden: &Garden, tater: &Tater) -> Result<String, ErrorType> {
+        if !self.annotate {
+            return self.render(garden)
+        }
+
+        let (start, end) = self.range(garden)?;
+        let mut lines = vec![];
+
+        for alloc in garden.allocations() {
+            let alloc_start = alloc.pos.max(start);
+            let alloc_end = (alloc.pos + alloc.len).min(end);
+
+            if alloc_start >= alloc_end {
+                continue
+            }
+
+            let chunks = self.chunks(garden, alloc_start, alloc_end)?;
+            lines.push(format!("{:>8}: {} ? {}", alloc_start, self.join(&chunks, " "),
+                Dump::name(alloc, garden, tater)));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    // Names an allocation after the '#define's pointing at exactly its bits,
+    // and the 'put' that pushed it along with the label it comes after.
+    fn name(alloc: &Allocation, garden: &Garden, tater: &Tater) -> String {
+        let mut names: Vec<String> = tater.defines().iter().filter(|def| {
+            let err = Error::new(def.1.clone(), 0, 1, None);
+            match Value::new(&def.1, &err, true) {
+                Ok(val) => match (val.get_ptr_position(garden), val.get_ptr_size(garden)) {
+                    (Ok(pos), Ok(len)) => pos == alloc.pos && len == alloc.len,
+                    _ => false,
+                },
+                Err(_) => false,
+            }
+        }).map(|def| def.0.clone()).collect();
+
+        let mut put = format!("{} bit(s)", alloc.len);
+
+        if let Some(span) = tater.span(alloc.instruction) {
+            put.push_str(&format!(" from '{}' at {}", span.text, span));
+        }
+
+        let label = tater.labels.iter()
+            .filter(|&(_, index)| *index <= alloc.instruction)
+            .max_by_key(|&(name, index)| (*index, name.clone()));
+
+        if let Some((name, _)) = label {
+            put.push_str(&format!(" after @{}", name));
+        }
+
+        names.push(put);
+        names.join(", ")
+    }
+}
@@ -1,8 +1,9 @@
 use super::value::Value;
 
+use std::error;
 use std::fmt;
-use std::process;
 
+#[derive(Clone, Debug)]
 pub enum ArgumentType {
     Exact(usize),
     Range(usize, usize),
@@ -10,10 +11,24 @@ pub enum ArgumentType {
     AtMost(usize),
 }
 
+#[derive(Clone, Debug)]
 pub enum ErrorType {
     Generic(String),
+    Io(String),
+    Image(String),
     InvalidValue(String),
     InvalidPointer(Value),
+    DivisionByZero,
+    EmptyCallStack,
+    Overflow {
+        size: usize,
+        target: usize,
+    },
+    OutOfBounds {
+        start: usize,
+        end: usize,
+        len: usize,
+    },
     Empty(String),
     ArgumentError {
         type_name: String,
@@ -25,12 +40,34 @@ pub enum ErrorType {
         type_name: String,
         value: String,
     },
+    Duplicate {
+        type_name: String,
+        value: String,
+        first: Span,
+    },
+    Multiple(Vec<TaterError>),
 }
 
+// Where a line of source, and the instruction compiled from it, came from.
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+}
+
+#[derive(Clone, Debug)]
 pub struct Error {
-    text: String,
-    line: usize,
-    file: Option<String>,
+    span: Span,
+}
+
+// What parsing or running a program fails with; 'location' is the source
+// line responsible, when it is known.
+#[derive(Clone, Debug)]
+pub struct TaterError {
+    pub kind: ErrorType,
+    pub location: Option<Span>,
 }
 
 impl ArgumentType {
@@ -59,47 +96,126 @@ impl fmt::Display for ErrorType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ErrorType::Generic(ref generic) => write!(f, "Generic: {}!", generic),
+            ErrorType::Io(ref io) => write!(f, "IOError: {}!", io),
+            ErrorType::Image(ref image) => write!(f, "ImageError: {}!", image),
             ErrorType::InvalidValue(ref val) => write!(f, "InvalidValue: {}!", val),
             ErrorType::ArgumentError {
                 ref type_name, ref name, ref num, ref range,
             } => write!(f, "ArgumentError: {} '{}' was given {} argument(s) but expected {}!",
                 type_name, name, num, range),
             ErrorType::NonExistent {ref type_name, ref value} => write!(f, "NameError: no such {} of name '{}'!", type_name, value),
+            ErrorType::Duplicate {ref type_name, ref value, ref first} => write!(f,
+                "NameError: {} '{}' is already defined at {}!", type_name, value, first),
+            ErrorType::Multiple(ref errors) => {
+                let lines: Vec<String> = errors.iter().map(|e| format!("{}", e)).collect();
+                write!(f, "{}", lines.join("\n"))
+            },
             ErrorType::Empty(ref name) => write!(f, "{} is empty!", name),
             ErrorType::InvalidPointer(ref ptr) => write!(f, "{} is an invalid pointer!", ptr),
+            ErrorType::DivisionByZero => write!(f, "ZeroDivisionError: division by zero!"),
+            ErrorType::EmptyCallStack => write!(f, "StackError: attempt to return on empty call stack!"),
+            ErrorType::Overflow {ref size, ref target} => write!(f,
+                "OverflowError: {} bit(s) don't fit in {} bit(s)!", size, target),
+            ErrorType::OutOfBounds {ref start, ref end, ref len} => write!(f,
+                "StackError: bits {}->{} are out of bounds for stack of {} bit(s)!", start, end, len),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref name) => write!(f, "{}:{}:{}", name, self.line, self.column),
+            None => write!(f, "{}:{}", self.line, self.column),
+        }
+    }
+}
+
+impl fmt::Display for TaterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let ErrorType::Multiple(_) = self.kind {
+            return write!(f, "{}", self.kind)
+        }
+
+        match self.location {
+            Some(ref loc) => write!(f,
+                "Error on line {}, column {}{}, {}.\n>>> {}",
+                loc.line, loc.column, match loc.file {
+                    Some(ref name) => format!(" in file '{}'!", name),
+                    None => "".to_string()
+                }, self.kind, loc.text,
+            ),
+            None => write!(f, "Error, {}.", self.kind),
+        }
+    }
+}
+
+impl error::Error for TaterError {}
+
+impl From<ErrorType> for TaterError {
+    fn from(kind: ErrorType) -> TaterError {
+        TaterError {
+            kind: kind,
+            location: None,
+        }
+    }
+}
+
+impl TaterError {
+    // Combines a list of errors into one, if there are any.
+    pub fn collect(mut errors: Vec<TaterError>) -> Result<(), TaterError> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(TaterError::from(ErrorType::Multiple(errors))),
+        }
+    }
+
+    // Attaches 'span' unless the error already knows where it came from.
+    pub fn at(self, span: &Span) -> TaterError {
+        match self.location {
+            Some(_) => self,
+            None => TaterError {
+                kind: self.kind,
+                location: Some(span.clone()),
+            },
         }
     }
 }
 
 #[allow(dead_code)]
 impl Error {
-    pub fn new(text: String, line: usize, path: Option<String>) -> Error {
+    pub fn new(text: String, line: usize, column: usize, path: Option<String>) -> Error {
         Error {
-            text: text,
-            line: line,
-            file: path,
+            span: Span {
+                file: path,
+                line: line,
+                column: column,
+                text: text,
+            },
         }
     }
 
-    pub fn throw(&self, err_type: ErrorType) -> ! {
-        println!(
-            "Error on line {}{}, {}.\n>>> {}",
-            self.line, match self.file {
-                Some(ref name) => format!(" in file '{}'!", name),
-                None => "".to_string()
-            }, err_type, self.text,
-        );
-        process::exit(0)
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn error(&self, err_type: ErrorType) -> TaterError {
+        TaterError {
+            kind: err_type,
+            location: Some(self.span.clone()),
+        }
     }
 
-    pub fn check_args(&self, err_type: &str, name: &str, num: usize, range: ArgumentType) {
+    pub fn check_args(&self, err_type: &str, name: &str, num: usize, range: ArgumentType) -> Result<(), TaterError> {
         if !range.is_valid(num) {
-            self.throw(ErrorType::ArgumentError {
+            return Err(self.error(ErrorType::ArgumentError {
                 type_name: err_type.to_string(),
                 name: name.to_string(),
                 num: num,
                 range: range,
-            })
+            }))
         }
+        Ok(())
     }
 }
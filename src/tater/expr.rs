@@ -0,0 +1,392 @@
+extern crate gmp;
+
+use super::error::*;
+
+use self::gmp::mpz::Mpz;
+
+use std::str::FromStr;
+
+// How many defines deep a name may be looked up, to catch ones defined in
+// terms of themselves.
+const DEPTH: usize = 32;
+
+// How deeply parentheses and unary operators may nest, counted across the
+// defines a name expands into, so that a long '((((' or '!!!!' fails rather
+// than overflowing the stack.
+const NESTING: usize = 64;
+
+// Constant expressions of '#if', over whole numbers:
+//     a || b, a && b, !a              0 is false, anything else true
+//     == != < > <= >=                 1 if it holds, 0 otherwise
+//     + - * / %  and  ( )
+//     defined NAME, defined(NAME)     1 if NAME has been defined
+// Any other name stands for what it was defined as, or 0 if it wasn't,
+// like in the C preprocessor.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(Mpz),
+    Name(String),
+    Op(&'static str),
+}
+
+struct Parser<'a> {
+    expr: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    defines: &'a [(String, String)],
+    depth: usize,
+    nesting: usize,
+}
+
+const OPS: [&'static str; 16] = [
+    "||", "&&", "==", "!=", "<=", ">=",
+    "<", ">", "+", "-", "*", "/", "%", "!", "(", ")",
+];
+
+pub fn evaluate(expr: &str, defines: &[(String, String)]) -> Result<Mpz, ErrorType> {
+    evaluate_at(expr, defines, 0, 0)
+}
+
+fn evaluate_at(expr: &str, defines: &[(String, String)], depth: usize, nesting: usize) -> Result<Mpz, ErrorType> {
+    let mut parser = Parser {
+        expr: expr,
+        tokens: tokenize(expr)?,
+        pos: 0,
+        defines: defines,
+        depth: depth,
+        nesting: nesting,
+    };
+
+    if parser.tokens.is_empty() {
+        return Err(ErrorType::Empty("'#if' expression".to_string()))
+    }
+
+    let val = parser.or()?;
+
+    match parser.tokens.get(parser.pos) {
+        Some(token) => Err(parser.error(&format!("unexpected {}", describe(token)))),
+        None => Ok(val),
+    }
+}
+
+fn invalid(expr: &str, message: &str) -> ErrorType {
+    ErrorType::InvalidValue(format!("'{}' is not a constant expression, {}", expr, message))
+}
+
+fn describe(token: &Token) -> String {
+    match *token {
+        Token::Num(ref num) => format!("'{}'", num),
+        Token::Name(ref name) => format!("'{}'", name),
+        Token::Op(op) => format!("'{}'", op),
+    }
+}
+
+fn truth(val: bool) -> Mpz {
+    match val {
+        true  => Mpz::one(),
+        false => Mpz::zero(),
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, ErrorType> {
+    let mut tokens = vec![];
+    let mut rest = expr.trim_start();
+
+    while rest != "" {
+        let c = rest.chars().next().unwrap();
+
+        let len = if c.is_alphanumeric() || c == '_' {
+            let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let word = &rest[.. len];
+
+            tokens.push(match c.is_digit(10) {
+                true => match Mpz::from_str(word) {
+                    Ok(num) => Token::Num(num),
+                    Err(_) => return Err(invalid(expr, &format!("'{}' is not a number", word))),
+                },
+                false => Token::Name(word.to_string()),
+            });
+            len
+        } else {
+            match OPS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    op.len()
+                },
+                None => return Err(invalid(expr, &format!("'{}' is not an operator", c))),
+            }
+        };
+
+        rest = rest[len ..].trim_start();
+    }
+    Ok(tokens)
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        match self.peek() {
+            Some(&Token::Op(o)) if o == op => {
+                self.pos += 1;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), ErrorType> {
+        match self.eat(op) {
+            true  => Ok(()),
+            false => Err(self.error(&format!("expected '{}'", op))),
+        }
+    }
+
+    fn error(&self, message: &str) -> ErrorType {
+        invalid(self.expr, message)
+    }
+
+    fn or(&mut self) -> Result<Mpz, ErrorType> {
+        let mut val = self.and()?;
+        while self.eat("||") {
+            let right = self.and()?;
+            val = truth(val != Mpz::zero() || right != Mpz::zero());
+        }
+        Ok(val)
+    }
+
+    fn and(&mut self) -> Result<Mpz, ErrorType> {
+        let mut val = self.compare()?;
+        while self.eat("&&") {
+            let right = self.compare()?;
+            val = truth(val != Mpz::zero() && right != Mpz::zero());
+        }
+        Ok(val)
+    }
+
+    fn compare(&mut self) -> Result<Mpz, ErrorType> {
+        let mut val = self.sum()?;
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Op(op)) if ["==", "!=", "<", ">", "<=", ">="].contains(&op) => op,
+                _ => return Ok(val),
+            };
+            self.pos += 1;
+
+            let right = self.sum()?;
+            val = truth(match op {
+                "==" => val == right,
+                "!=" => val != right,
+                "<"  => val < right,
+                ">"  => val > right,
+                "<=" => val <= right,
+                _    => val >= right,
+            });
+        }
+    }
+
+    fn sum(&mut self) -> Result<Mpz, ErrorType> {
+        let mut val = self.product()?;
+        loop {
+            if self.eat("+") {
+                val = val + self.product()?;
+            } else if self.eat("-") {
+                val = val - self.product()?;
+            } else {
+                return Ok(val)
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Mpz, ErrorType> {
+        let mut val = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Op(op)) if op == "*" || op == "/" || op == "%" => op,
+                _ => return Ok(val),
+            };
+            self.pos += 1;
+
+            let right = self.unary()?;
+            if op != "*" && right == Mpz::zero() {
+                return Err(ErrorType::DivisionByZero)
+            }
+
+            val = match op {
+                "*" => val * right,
+                "/" => val / right,
+                _   => val % right,
+            };
+        }
+    }
+
+    // Every operator and parenthesis nested in another goes through here,
+    // so this is where the nesting is counted.
+    fn unary(&mut self) -> Result<Mpz, ErrorType> {
+        if self.nesting >= NESTING {
+            return Err(self.error("it is nested too deeply"))
+        }
+
+        self.nesting += 1;
+        let val = self.unary_at();
+        self.nesting -= 1;
+        val
+    }
+
+    fn unary_at(&mut self) -> Result<Mpz, ErrorType> {
+        if self.eat("!") {
+            return Ok(truth(self.unary()? == Mpz::zero()))
+        }
+        if self.eat("-") {
+            return Ok(-self.unary()?)
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Mpz, ErrorType> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(self.error("it ends too early")),
+        };
+        self.pos += 1;
+
+        match token {
+            Token::Num(num) => Ok(num),
+            Token::Op("(") => {
+                let val = self.or()?;
+                self.expect(")")?;
+                Ok(val)
+            },
+            Token::Name(ref name) if name == "defined" => {
+                let parens = self.eat("(");
+                let name = match self.peek() {
+                    Some(&Token::Name(ref name)) => name.clone(),
+                    _ => return Err(self.error("'defined' needs a name")),
+                };
+                self.pos += 1;
+
+                if parens {
+                    self.expect(")")?;
+                }
+                Ok(truth(self.defines.iter().any(|def| def.0 == name)))
+            },
+            Token::Name(name) => match self.defines.iter().find(|def| def.0 == name) {
+                Some(def) if self.depth >= DEPTH => Err(ErrorType::InvalidValue(
+                    format!("'{}' is defined in terms of itself", def.0))),
+                Some(def) => evaluate_at(&def.1, self.defines, self.depth + 1, self.nesting),
+                None => Ok(Mpz::zero()),
+            },
+            token => Err(self.error(&format!("unexpected {}", describe(&token)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defines(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn eval(expr: &str, pairs: &[(&str, &str)]) -> Result<i64, ErrorType> {
+        evaluate(expr, &defines(pairs)).map(|num| i64::from_str(&num.to_string()).unwrap())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[]).unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3", &[]).unwrap(), 9);
+        assert_eq!(eval("10 - 4 - 3", &[]).unwrap(), 3);
+        assert_eq!(eval("7 % 4 * 2", &[]).unwrap(), 6);
+        assert_eq!(eval("-2 * -3", &[]).unwrap(), 6);
+        assert_eq!(eval("1 + 1 == 2 && 3 > 2", &[]).unwrap(), 1);
+        assert_eq!(eval("1 < 2 == 1", &[]).unwrap(), 1);
+
+        let vars = [("a", "0"), ("b", "1"), ("c", "1")];
+        assert_eq!(eval("!a && b || c", &vars).unwrap(), 1);
+        assert_eq!(eval("!a && !b || !c", &vars).unwrap(), 0);
+        assert_eq!(eval("a && b || c", &vars).unwrap(), 1);
+        assert_eq!(eval("a && (b || c)", &vars).unwrap(), 0);
+        assert_eq!(eval("!!5", &[]).unwrap(), 1);
+    }
+
+    #[test]
+    fn defined() {
+        let vars = [("X", "")];
+        assert_eq!(eval("defined X", &vars).unwrap(), 1);
+        assert_eq!(eval("defined(X)", &vars).unwrap(), 1);
+        assert_eq!(eval("defined ( X )", &vars).unwrap(), 1);
+        assert_eq!(eval("defined Y", &vars).unwrap(), 0);
+        assert_eq!(eval("!defined(Y) && defined X", &vars).unwrap(), 1);
+
+        assert!(eval("defined", &vars).is_err());
+        assert!(eval("defined(X", &vars).is_err());
+        assert!(eval("defined 1", &vars).is_err());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(eval("UNDEFINED", &[]).unwrap(), 0);
+        assert_eq!(eval("UNDEFINED + 4", &[]).unwrap(), 4);
+
+        let vars = [("A", "2 + 3"), ("B", "A * 2")];
+        assert_eq!(eval("B", &vars).unwrap(), 10);
+        assert_eq!(eval("A * A", &vars).unwrap(), 25);
+
+        // the first define of a name is the one used
+        assert_eq!(eval("A", &[("A", "1"), ("A", "2")]).unwrap(), 1);
+    }
+
+    #[test]
+    fn self_referential_defines() {
+        assert!(eval("A", &[("A", "A + 1")]).is_err());
+        assert!(eval("A", &[("A", "B"), ("B", "A")]).is_err());
+    }
+
+    #[test]
+    fn nesting() {
+        let deep = |depth: usize, open: &str, close: &str| format!("{}1{}", open.repeat(depth), close.repeat(depth));
+
+        assert_eq!(eval(&deep(60, "(", ")"), &[]).unwrap(), 1);
+        assert_eq!(eval(&deep(30, "!!", ""), &[]).unwrap(), 1);
+        assert_eq!(eval(&deep(30, "-(", ")"), &[]).unwrap(), 1);
+        assert!(eval(&deep(NESTING, "(", ")"), &[]).is_err());
+
+        assert!(eval(&deep(100000, "(", ")"), &[]).is_err());
+        assert!(eval(&deep(100000, "!", ""), &[]).is_err());
+        assert!(eval(&deep(100000, "-", ""), &[]).is_err());
+
+        // nesting carries on into what names are defined as
+        let inner = deep(40, "(", ")");
+        assert_eq!(eval(&format!("{}A{}", "(".repeat(20), ")".repeat(20)), &[("A", &inner)]).unwrap(), 1);
+        assert!(eval(&format!("{}A{}", "(".repeat(40), ")".repeat(40)), &[("A", &inner)]).is_err());
+    }
+
+    #[test]
+    fn division_by_zero() {
+        match eval("1 / 0", &[]) {
+            Err(ErrorType::DivisionByZero) => {},
+            other => panic!("{:?}", other),
+        }
+        match eval("1 % (2 - 2)", &[]) {
+            Err(ErrorType::DivisionByZero) => {},
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(eval("0 * (1 / 1)", &[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(eval("", &[]).is_err());
+        assert!(eval("1 2", &[]).is_err());
+        assert!(eval("1 + 2)", &[]).is_err());
+        assert!(eval("(1 + 2", &[]).is_err());
+        assert!(eval("1 +", &[]).is_err());
+        assert!(eval("1 = 2", &[]).is_err());
+        assert!(eval("1 $ 2", &[]).is_err());
+        assert!(eval("12abc", &[]).is_err());
+    }
+}
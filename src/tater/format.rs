@@ -0,0 +1,360 @@
+use super::tater::{COMMENT_CHAR, ARGUMENT_CHAR};
+
+// What a line of source holds, besides its comment.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Blank,
+    Macro(String, String),
+    Label(String),
+    Instruction(String, Vec<String>),
+}
+
+// A line of source, with the text after its '?' if it has one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub item: Item,
+    pub comment: Option<String>,
+}
+
+// A whole file, kept line by line so that it can be written back out with
+// nothing but its layout changed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Source {
+    pub lines: Vec<Line>,
+}
+
+impl Line {
+    // Splits a line the way 'Tater::parse_line' does, without expanding
+    // strings or defines.
+    pub fn parse(text: &str) -> Line {
+        let (code, comment) = match text.find(COMMENT_CHAR) {
+            Some(pos) => (&text[.. pos], Some(text[pos + 1 ..].trim_end().to_string())),
+            None => (text, None),
+        };
+        let code = code.trim();
+
+        let item = if code == "" {
+            Item::Blank
+        } else if code.starts_with('#') {
+            let text = code[1 ..].trim();
+            let name = text.split_whitespace().next().unwrap_or("");
+            Item::Macro(name.to_string(), text[name.len() ..].trim().to_string())
+        } else if code.starts_with('@') {
+            Item::Label(code[1 ..].to_string())
+        } else {
+            let name = code.split_whitespace().next().unwrap();
+            let args = split_outside_strings(&code[name.len() ..]).into_iter()
+                .map(|arg| arg.trim().to_string())
+                .filter(|arg| arg != "")
+                .collect();
+            Item::Instruction(name.to_string(), args)
+        };
+
+        Line {
+            item: item,
+            comment: comment,
+        }
+    }
+
+    // The line without its comment.
+    pub fn code(&self) -> String {
+        match self.item {
+            Item::Blank => String::new(),
+            Item::Macro(ref name, ref args) => match (name.as_str(), args.as_str()) {
+                (_, "") => format!("#{}", name),
+                ("define", _) => {
+                    let define = args.split_whitespace().next().unwrap();
+                    let value = canonical(&args[define.len() ..]);
+                    format!("#define {} {}", define, value).trim_end().to_string()
+                },
+                _ => format!("#{} {}", name, args),
+            },
+            Item::Label(ref name) => format!("@{}", name),
+            Item::Instruction(ref name, ref args) => match args.len() {
+                0 => name.clone(),
+                _ => {
+                    let args: Vec<String> = args.iter().map(|arg| canonical(arg)).collect();
+                    format!("{} {}", name, args.join(&format!("{} ", ARGUMENT_CHAR)))
+                },
+            },
+        }
+    }
+
+    fn comment(&self) -> Option<String> {
+        self.comment.as_ref().map(|text| match text.chars().next() {
+            Some(c) if !c.is_whitespace() => format!("{} {}", COMMENT_CHAR, text),
+            _ => format!("{}{}", COMMENT_CHAR, text),
+        })
+    }
+}
+
+impl Source {
+    pub fn parse(text: &str) -> Source {
+        Source {
+            lines: text.lines().map(Line::parse).collect(),
+        }
+    }
+
+    // Writes the source in the canonical style:
+    //     everything flush left but the bodies of macros and conditions,
+    //     indented by four for each level
+    //     single blank lines between blocks
+    //     a blank line before each label, unless a comment, another label
+    //     or the start of a macro or a branch of a condition leads into it
+    //     one space after an instruction and after each ';'
+    //     comments at the end of consecutive lines lined up with each other
+    pub fn render(&self) -> String {
+        let mut lines: Vec<&Line> = vec![];
+
+        for line in &self.lines {
+            let blank = line.item == Item::Blank && line.comment.is_none();
+
+            match lines.last() {
+                None if blank => continue,
+                Some(last) if blank && last.item == Item::Blank && last.comment.is_none() => continue,
+                _ => {},
+            }
+            lines.push(line);
+        }
+
+        while lines.last().map_or(false, |l| l.item == Item::Blank && l.comment.is_none()) {
+            lines.pop();
+        }
+
+        let empty = Line {
+            item: Item::Blank,
+            comment: None,
+        };
+
+        let mut spaced: Vec<&Line> = vec![];
+
+        for line in lines {
+            if let Item::Label(_) = line.item {
+                let leads = match spaced.last() {
+                    None => true,
+                    Some(last) => last.item == Item::Blank || match last.item {
+                        Item::Label(_) => true,
+                        Item::Macro(ref name, _) => ["macro", "if", "ifdef", "ifndef", "else"].contains(&name.as_str()),
+                        _ => false,
+                    },
+                };
+                if !leads {
+                    spaced.push(&empty);
+                }
+            }
+            spaced.push(line);
+        }
+
+        let mut depth: usize = 0;
+        let codes: Vec<String> = spaced.iter().map(|l| {
+            let indent = match l.item {
+                Item::Macro(ref name, _) => match name.as_str() {
+                    "macro" | "if" | "ifdef" | "ifndef" => {
+                        depth += 1;
+                        depth - 1
+                    },
+                    "end" | "endif" => {
+                        depth = depth.saturating_sub(1);
+                        depth
+                    },
+                    "else" => depth.saturating_sub(1),
+                    _ => depth,
+                },
+                Item::Blank if l.comment.is_none() => 0,
+                _ => depth,
+            };
+
+            format!("{}{}", "    ".repeat(indent), l.code())
+        }).collect();
+        let mut out = String::new();
+        let mut i = 0;
+
+        while i < spaced.len() {
+            // a run of lines with code and a comment after it
+            let mut end = i;
+            while end < spaced.len() && codes[end].trim() != "" && spaced[end].comment.is_some() {
+                end += 1;
+            }
+
+            if end > i {
+                let width = codes[i .. end].iter().map(|c| c.chars().count()).max().unwrap();
+                for j in i .. end {
+                    out.push_str(&format!("{:<2$} {}\n", codes[j], spaced[j].comment().unwrap(), width));
+                }
+                i = end;
+                continue
+            }
+
+            let line = match spaced[i].comment() {
+                Some(comment) => match codes[i].trim() {
+                    "" => codes[i].clone() + &comment,
+                    _ => format!("{} {}", codes[i], comment),
+                },
+                None => codes[i].clone(),
+            };
+            out.push_str(&line);
+            out.push('\n');
+            i += 1;
+        }
+        out
+    }
+}
+
+// Formats a file, see 'Source::render'.
+pub fn format_source(text: &str) -> String {
+    Source::parse(text).render()
+}
+
+// Splits arguments on ';' outside of pointers and string literals.
+fn split_outside_strings(text: &str) -> Vec<&str> {
+    let mut ret = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {},
+            }
+            continue
+        }
+
+        match c {
+            '"' => in_string = true,
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            c if c == ARGUMENT_CHAR && depth == 0 => {
+                ret.push(&text[start .. i]);
+                start = i + c.len_utf8();
+            },
+            _ => {},
+        }
+    }
+
+    ret.push(&text[start ..]);
+    ret
+}
+
+// Spaces an argument the canonical way: runs of whitespace become a single
+// space, none just inside brackets, and one after each ';'. Strings are left
+// as they are.
+fn canonical(text: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = text.trim().chars();
+    let mut space = false;
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            space = true;
+            continue
+        }
+
+        let after_space = ret.ends_with('[') || ret.ends_with(' ') || ret == "";
+        if space && !after_space && c != ']' && c != ARGUMENT_CHAR {
+            ret.push(' ');
+        }
+        space = false;
+        ret.push(c);
+
+        match c {
+            '"' => {
+                let mut escaped = false;
+                while let Some(c) = chars.next() {
+                    ret.push(c);
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => break,
+                        _ => {},
+                    }
+                }
+            },
+            c if c == ARGUMENT_CHAR => ret.push(' '),
+            _ => {},
+        }
+    }
+    ret.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_source;
+
+    // Formats each input, checking it against what's expected and that
+    // formatting that again changes nothing.
+    fn check(cases: &[(&str, &str)]) {
+        for &(input, expected) in cases {
+            let formatted = format_source(input);
+            assert_eq!(formatted, expected, "formatting {:?}", input);
+            assert_eq!(format_source(&formatted), formatted, "formatting {:?} again", formatted);
+        }
+    }
+
+    #[test]
+    fn spacing() {
+        check(&[
+            ("put 8;65", "put 8; 65\n"),
+            ("move [ 0 ;8 ];  [0;8]\n", "move [0; 8]; [0; 8]\n"),
+            ("  return  ", "return\n"),
+            ("#define  SIZE   [ 0;8 ]", "#define SIZE [0; 8]\n"),
+            ("", ""),
+        ]);
+    }
+
+    #[test]
+    fn comments() {
+        check(&[
+            ("put 8;65   ? letter\nmove [ 0 ;8 ];  [0;8] ?copy\n@loop\nadd [0;8];1?x",
+             "put 8; 65           ? letter\nmove [0; 8]; [0; 8] ? copy\n\n@loop\nadd [0; 8]; 1 ? x\n"),
+            ("? alone\n  ?  indented\nput 1 ? after", "? alone\n?  indented\nput 1 ? after\n"),
+            ("#macro m ? a\nput 1 ? b\n#end", "#macro m  ? a\n    put 1 ? b\n#end\n"),
+        ]);
+    }
+
+    #[test]
+    fn blank_lines() {
+        check(&[
+            ("\n\nput 1\n\n\n\nput 2\n\n\n", "put 1\n\nput 2\n"),
+            ("put 1\n\n? between\n\n\nput 2", "put 1\n\n? between\n\nput 2\n"),
+        ]);
+    }
+
+    #[test]
+    fn indentation() {
+        check(&[
+            ("#macro twice x\nadd x;x\n#if 1\nadd x ; 1\n#else\nsub x;1\n#endif\n#end\n#ifdef A\nput 1\n#endif",
+             "#macro twice x\n    add x; x\n    #if 1\n        add x; 1\n    #else\n        sub x; 1\n    #endif\n#end\n\
+              #ifdef A\n    put 1\n#endif\n"),
+            ("        #ifndef A\n  put 1\n        #endif", "#ifndef A\n    put 1\n#endif\n"),
+            ("#endif\nput 1", "#endif\nput 1\n"),
+        ]);
+    }
+
+    #[test]
+    fn strings() {
+        check(&[
+            ("put 8; \"a;b\"", "put 8; \"a;b\"\n"),
+            ("put 8;\"x ;  y\" ? semi", "put 8; \"x ;  y\" ? semi\n"),
+            ("extern print;\"; \"", "extern print; \"; \"\n"),
+            ("put 8;\"\\\";\"", "put 8; \"\\\";\"\n"),
+        ]);
+    }
+
+    #[test]
+    fn labels() {
+        check(&[
+            ("put 1\n@a\n@b\n? note\n@c", "put 1\n\n@a\n@b\n? note\n@c\n"),
+            ("@first\nput 1", "@first\nput 1\n"),
+            ("#macro m\n@inner\nput 1\n#end\n@after", "#macro m\n    @inner\n    put 1\n#end\n\n@after\n"),
+            ("#if 1\n@b\n#else\n@c\n#endif\n#ifdef X\n@d\n#endif\n#ifndef X\n@e\n#endif",
+             "#if 1\n    @b\n#else\n    @c\n#endif\n#ifdef X\n    @d\n#endif\n#ifndef X\n    @e\n#endif\n"),
+            ("#define Q 1\n@f", "#define Q 1\n\n@f\n"),
+            ("#require a.tat\n@g", "#require a.tat\n\n@g\n"),
+        ]);
+    }
+}
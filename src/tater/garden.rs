@@ -1,33 +1,53 @@
 extern crate gmp;
-extern crate time;
 
 use super::util::*;
-use super::tater::Tater;
+use super::bits::{BitStack, BitSlice};
+use super::error::ErrorType;
+use super::dump::Dump;
 
+use std::io;
+use std::io::BufRead;
 use std::mem::size_of;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Bits pushed onto the stack together, and the instruction that pushed them.
+#[derive(Clone, Debug)]
+pub struct Allocation {
+    pub pos: usize,
+    pub len: usize,
+    pub instruction: usize,
+}
 
 pub struct Garden {
-    stack: Vec<bool>,
+    stack: BitStack,
     call_stack: Vec<usize>,
+    allocations: Vec<Allocation>,
 
     pub instruction: usize,
     pub validity: bool,
-    pub input_string: String,
+    // bytes of stdin read but not used yet, which needn't be utf-8
+    pub input_string: Vec<u8>,
     pub rand_state: gmp::rand::RandState,
 }
 
 #[allow(dead_code)]
 impl Garden {
     pub fn new() -> Garden {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Garden::with_seed(now)
+    }
+
+    pub fn with_seed(seed: u64) -> Garden {
         let mut ret = Garden {
-            stack: Vec::new(),
+            stack: BitStack::new(),
             call_stack: Vec::new(),
+            allocations: Vec::new(),
             instruction: 0,
             validity: true,
-            input_string: "".to_string(),
+            input_string: vec![],
             rand_state: gmp::rand::RandState::new(),
         };
-        ret.rand_state.seed_ui(time::get_time().sec as u64);
+        ret.rand_state.seed_ui(seed);
         ret
     }
 
@@ -35,67 +55,214 @@ impl Garden {
         self.stack.len()
     }
 
-    pub fn push(&mut self, bits: usize, value: bool) {
-        let len = self.stack.len();
-        self.stack.resize(len + bits, value);
+    pub fn push(&mut self, bits: usize, value: bool) -> Result<(), ErrorType> {
+        let pos = self.stack.len();
+        self.stack.push(bits, value)?;
+
+        if bits > 0 {
+            self.allocations.push(Allocation {
+                pos: pos,
+                len: bits,
+                instruction: self.instruction.saturating_sub(1),
+            });
+        }
+        Ok(())
     }
 
-    pub fn pop(&mut self, bits: usize) {
+    pub fn pop(&mut self, bits: usize) -> Result<(), ErrorType> {
         let len = self.stack.len();
-        self.stack.truncate(len - bits);
+        let new_len = len.checked_sub(bits).ok_or(ErrorType::OutOfBounds {
+            start: 0,
+            end: bits,
+            len: len,
+        })?;
+
+        self.stack.truncate(new_len);
+
+        while let Some(mut last) = self.allocations.pop() {
+            if last.pos < new_len {
+                last.len = last.len.min(new_len - last.pos);
+                self.allocations.push(last);
+                break
+            }
+        }
+        Ok(())
+    }
+
+    // What is currently on the stack, in the order it was pushed.
+    pub fn allocations(&self) -> &[Allocation] {
+        &self.allocations
+    }
+
+    // The end of the 'len' bits at 'pos', failing rather than overflowing.
+    pub fn end_of(&self, pos: usize, len: usize) -> Result<usize, ErrorType> {
+        pos.checked_add(len).ok_or(ErrorType::OutOfBounds {
+            start: pos,
+            end: usize::max_value(),
+            len: self.stack.len(),
+        })
     }
 
-    pub fn slice(&self, start: usize, end: usize) -> &[bool] {
-        &self.stack[start .. end]
+    fn check_range(&self, pos: usize, len: usize) -> Result<(), ErrorType> {
+        let end = self.end_of(pos, len)?;
+        if end > self.stack.len() {
+            return Err(ErrorType::OutOfBounds {
+                start: pos,
+                end: end,
+                len: self.stack.len(),
+            })
+        }
+        Ok(())
+    }
+
+    pub fn slice(&self, start: usize, end: usize) -> Result<BitSlice<'_>, ErrorType> {
+        if start > end {
+            return Err(ErrorType::OutOfBounds {
+                start: start,
+                end: end,
+                len: self.stack.len(),
+            })
+        }
+        self.check_range(start, end - start)?;
+        Ok(self.stack.slice(start, end))
     }
 
     pub fn print_bytes(&self, bits_per_byte: usize) {
-        for i in 0 .. (self.stack_len() / bits_per_byte) {
-            let bits = self.slice(i * bits_per_byte, (i + 1) * bits_per_byte);
-            let num = boolvec_to_bignum(bits);
+        match Dump::new(bits_per_byte).render(self) {
+            Ok(dump) => println!("{}", dump),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    pub fn set_bits_boolvec(&mut self, num: &[bool], pos: usize, len: usize) -> Result<(), ErrorType> {
+        self.check_range(pos, len)?;
+        self.stack.write_bits(pos, num, len);
+        Ok(())
+    }
+
+    pub fn set_bits_usize(&mut self, num: usize, pos: usize, len: usize) -> Result<(), ErrorType> {
+        self.check_range(pos, len)?;
+
+        let bits = len.min(size_of::<usize>() * 8);
+        self.stack.write(pos, bits, num as u64);
+        self.stack.fill(pos + bits, len - bits, false);
+        Ok(())
+    }
+
+    pub fn fill_bits(&mut self, pos: usize, len: usize, value: bool) -> Result<(), ErrorType> {
+        self.check_range(pos, len)?;
+        self.stack.fill(pos, len, value);
+        Ok(())
+    }
+
+    // Copies 'from_len' bits at 'from' into the 'to_len' bits at 'to',
+    // zero extending or truncating them.
+    pub fn copy_bits(&mut self, from: usize, from_len: usize, to: usize, to_len: usize) -> Result<(), ErrorType> {
+        self.check_range(from, from_len)?;
+        self.check_range(to, to_len)?;
+
+        let len = from_len.min(to_len);
+        self.stack.copy(from, to, len);
+        self.stack.fill(to + len, to_len - len, false);
+        Ok(())
+    }
+
+    // Sets 'out' to the 'len' bits at 'pos' as 64 bit words.
+    pub fn read_words(&self, pos: usize, len: usize, out: &mut Vec<u64>) -> Result<(), ErrorType> {
+        self.check_range(pos, len)?;
+        out.clear();
+        self.stack.read_words(pos, len, out);
+        Ok(())
+    }
+
+    pub fn write_words(&mut self, pos: usize, len: usize, words: &[u64]) -> Result<(), ErrorType> {
+        self.check_range(pos, len)?;
+        self.stack.write_words(pos, len, words);
+        Ok(())
+    }
+
+    pub fn equal_bits(&self, a: usize, a_len: usize, b: usize, b_len: usize) -> Result<bool, ErrorType> {
+        self.check_range(a, a_len)?;
+        self.check_range(b, b_len)?;
+        Ok(self.stack.equal(a, a_len, b, b_len))
+    }
+
+    pub fn load_bignum(&self, pos: usize, len: usize, out: &mut gmp::mpz::Mpz) -> Result<(), ErrorType> {
+        self.check_range(pos, len)?;
+        self.stack.load_bignum(pos, len, out);
+        Ok(())
+    }
+
+    // Stores 'num' wrapped to 'len' bits, so negative numbers end up in
+    // two's complement.
+    pub fn set_bits_bignum(&mut self, num: &gmp::mpz::Mpz, pos: usize, len: usize) -> Result<(), ErrorType> {
+        self.check_range(pos, len)?;
 
-            print!("{}, ", num);
+        if *num < gmp::mpz::Mpz::zero() {
+            let mut wrapped = num.clone();
+            truncate_bits(&mut wrapped, len);
+            self.stack.store_bignum(&wrapped, pos, len);
+        } else {
+            self.stack.store_bignum(num, pos, len);
         }
-        println!("");
+        Ok(())
     }
 
-    pub fn set_bits_boolvec(&mut self, num: &[bool], pos: usize, len: usize) {
-        for i in 0 .. len {
-            self.stack[pos + i] = match i < num.len() {
-                true => num[i],
-                false => false,
-            }
+    // Buffers the next line of stdin in 'input_string' once the previous
+    // one is used up; returns false at EOF.
+    fn fill_input(&mut self) -> Result<bool, ErrorType> {
+        if self.input_string.is_empty() {
+            let stdin = io::stdin();
+            stdin.lock().read_until(b'\n', &mut self.input_string).map_err(
+                |e| ErrorType::Io(e.to_string()))?;
         }
+        Ok(!self.input_string.is_empty())
     }
 
-    pub fn set_bits_usize(&mut self, num: usize, pos: usize, len: usize) {
-        for i in 0 .. len {
-            self.stack[pos + i] = match i < size_of::<usize>() * 8 {
-                true => num & (1 << i) != 0,
-                false => false,
-            }
+    // The rest of the line, without its line ending.
+    pub fn read_line(&mut self) -> Result<Option<Vec<u8>>, ErrorType> {
+        if !self.fill_input()? {
+            return Ok(None)
+        }
+
+        let mut line: Vec<u8> = self.input_string.drain(..).collect();
+
+        if line.last() == Some(&b'\n') {
+            line.pop();
         }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        Ok(Some(line))
+    }
+
+    pub fn read_byte(&mut self) -> Result<Option<u8>, ErrorType> {
+        if !self.fill_input()? {
+            return Ok(None)
+        }
+
+        let b = self.input_string.remove(0);
+        Ok(Some(b))
     }
 
-    pub fn set_bits_bignum(&mut self, num: &gmp::mpz::Mpz, pos: usize, len: usize) {
-        self.set_bits_boolvec(&bignum_to_boolvec(num), pos, len);
+    // Return addresses of the calls currently being run, innermost last.
+    pub fn call_stack(&self) -> &[usize] {
+        &self.call_stack
     }
 
-    pub fn call(&mut self, tater: &Tater, name: &str) {
+    pub fn call(&mut self, target: usize) {
         self.call_stack.push(self.instruction);
-        self.instruction = *tater.labels.get(name).expect(
-            format!("No such label of name {}!", name).as_ref()
-        );
+        self.instruction = target;
     }
 
-    pub fn ret(&mut self) {
-        let pos = self.call_stack.pop().expect("Attempt to return on empty 'call_stack'!");
+    pub fn ret(&mut self) -> Result<(), ErrorType> {
+        let pos = self.call_stack.pop().ok_or(ErrorType::EmptyCallStack)?;
         self.instruction = pos;
+        Ok(())
     }
 
-    pub fn goto(&mut self, tater: &Tater, name: &str) {
-        self.instruction = *tater.labels.get(name).expect(
-            format!("No such label of name {}!", name).as_ref()
-        );
+    pub fn goto(&mut self, target: usize) {
+        self.instruction = target;
     }
 }
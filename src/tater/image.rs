@@ -0,0 +1,754 @@
+extern crate gmp;
+
+use super::value::Value;
+use super::bytecode::{Program, Code, Op, Operand, Constant, Arith, Logic, Compare};
+use super::error::*;
+
+use self::gmp::mpz::Mpz;
+
+// Compiled programs as written by 'tater build'. An image holds everything
+// 'Tater::run' needs without the source:
+//     header        "TATC" and the version as a little endian u32
+//     source map    a span and canonical source for every instruction
+//     labels        names, the instruction they point at and their span
+//     defines       kept to name allocations in stack dumps
+//     program       the bytecode and the constants, values and externs
+//                   it refers to
+// Numbers are little endian u64s, strings are prefixed with their length.
+// Images of any other version are refused rather than guessed at, so
+// 'VERSION' has to change along with the layout.
+pub const MAGIC: &'static [u8] = b"TATC";
+pub const VERSION: u32 = 1;
+
+// How deeply positions and pointers may nest in a value, so that a damaged
+// image can't make the reader recurse without bound.
+const VALUE_DEPTH: usize = 64;
+
+pub struct Image {
+    pub spans: Vec<Span>,
+    pub sources: Vec<String>,
+    pub labels: Vec<(String, usize, Span)>,
+    pub defines: Vec<(String, String)>,
+    pub program: Program,
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+pub fn is_image(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn invalid(message: &str) -> ErrorType {
+    ErrorType::Image(format!("not a valid image, {}", message))
+}
+
+impl Image {
+    pub fn write(&self) -> Vec<u8> {
+        let mut w = Writer {
+            bytes: MAGIC.to_vec(),
+        };
+        w.bytes.extend_from_slice(&VERSION.to_le_bytes());
+
+        w.usize(self.spans.len());
+        for (span, source) in self.spans.iter().zip(&self.sources) {
+            w.span(span);
+            w.string(source);
+        }
+
+        w.usize(self.labels.len());
+        for &(ref name, index, ref span) in &self.labels {
+            w.string(name);
+            w.usize(index);
+            w.span(span);
+        }
+
+        w.usize(self.defines.len());
+        for &(ref name, ref value) in &self.defines {
+            w.string(name);
+            w.string(value);
+        }
+
+        let program = &self.program;
+
+        w.usize(program.constants.len());
+        for constant in &program.constants {
+            w.bignum(&constant.num);
+            w.usize(constant.size);
+            w.usize(constant.words.len());
+            for word in &constant.words {
+                w.u64(*word);
+            }
+        }
+
+        w.usize(program.values.len());
+        for value in &program.values {
+            w.value(value);
+        }
+
+        w.usize(program.externs.len());
+        for name in &program.externs {
+            w.string(name);
+        }
+
+        w.usize(program.code.len());
+        for code in &program.code {
+            w.code(code);
+        }
+
+        w.bytes
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<Image, ErrorType> {
+        if !is_image(bytes) {
+            return Err(invalid("it doesn't start with 'TATC'"))
+        }
+
+        let mut r = Reader {
+            bytes: bytes,
+            pos: MAGIC.len(),
+        };
+
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(ErrorType::Image(format!(
+                "the image is version {} but this tater reads version {}, rebuild it with 'tater build'",
+                version, VERSION)))
+        }
+
+        let mut image = Image {
+            spans: vec![],
+            sources: vec![],
+            labels: vec![],
+            defines: vec![],
+            program: Program::new(),
+        };
+
+        for _ in 0 .. r.len()? {
+            image.spans.push(r.span()?);
+            image.sources.push(r.string()?);
+        }
+
+        for _ in 0 .. r.len()? {
+            image.labels.push((r.string()?, r.usize()?, r.span()?));
+        }
+
+        for _ in 0 .. r.len()? {
+            image.defines.push((r.string()?, r.string()?));
+        }
+
+        for _ in 0 .. r.len()? {
+            let num = r.bignum()?;
+            let size = r.usize()?;
+
+            let mut words = vec![];
+            for _ in 0 .. r.len()? {
+                words.push(r.u64()?);
+            }
+
+            image.program.constants.push(Constant {
+                num: num,
+                words: words,
+                size: size,
+            });
+        }
+
+        for _ in 0 .. r.len()? {
+            let value = r.value(0)?;
+            image.program.values.push(value);
+        }
+
+        for _ in 0 .. r.len()? {
+            let name = r.string()?;
+            image.program.externs.push(name);
+        }
+
+        for _ in 0 .. r.len()? {
+            let code = r.code()?;
+            image.program.code.push(code);
+        }
+
+        if r.pos != bytes.len() {
+            return Err(invalid("it has trailing bytes"))
+        }
+
+        image.check()?;
+        Ok(image)
+    }
+
+    // Makes sure every index in the image points at something, so that a
+    // damaged image fails here instead of panicking while it runs.
+    fn check(&self) -> Result<(), ErrorType> {
+        let program = &self.program;
+        let len = program.code.len();
+
+        if self.spans.len() != len {
+            return Err(invalid("its source map doesn't match its code"))
+        }
+
+        if self.labels.iter().any(|&(_, index, _)| index > len) {
+            return Err(invalid("a label points past the end of the program"))
+        }
+
+        let operand = |op: &Operand| match *op {
+            Operand::Const(i) => i < program.constants.len(),
+            Operand::Value(i) => i < program.values.len(),
+            Operand::Ptr {..} | Operand::RevPtr {..} => true,
+        };
+
+        for code in &program.code {
+            let valid = match code.op {
+                Op::Nop | Op::Return => true,
+                Op::Put {ref size, ref value} => operand(size) && value.as_ref().map_or(true, &operand),
+                Op::Pop(ref op) | Op::Zero(ref op) => operand(op),
+                Op::Move {ref to, ref from} | Op::Not {ref to, ref from} => operand(to) && operand(from),
+                Op::Arith {ref to, ref left, ref right, ..} | Op::Logic {ref to, ref left, ref right, ..} =>
+                    operand(to) && operand(left) && operand(right),
+                Op::Random {ref to, ref bound} => operand(to) && bound.as_ref().map_or(true, &operand),
+                Op::Shift {ref to, ref from, ref amount, ..} => operand(to) && operand(from) && operand(amount),
+                Op::Compare {ref a, ref b, ..} => operand(a) && operand(b),
+                Op::Call(index) | Op::Jump(index) | Op::JumpIf(index) | Op::JumpUnless(index) => index <= len,
+                Op::Extern {call, value} => call < program.externs.len() && value < program.values.len(),
+            };
+
+            if !valid {
+                return Err(invalid("an instruction refers to something that isn't there"))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Writer {
+    fn u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    fn u64(&mut self, val: u64) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn usize(&mut self, val: usize) {
+        self.u64(val as u64);
+    }
+
+    fn string(&mut self, val: &str) {
+        self.usize(val.len());
+        self.bytes.extend_from_slice(val.as_bytes());
+    }
+
+    fn span(&mut self, span: &Span) {
+        match span.file {
+            Some(ref file) => {
+                self.bool(true);
+                self.string(file);
+            },
+            None => self.bool(false),
+        }
+        self.usize(span.line);
+        self.usize(span.column);
+        self.string(&span.text);
+    }
+
+    fn bignum(&mut self, num: &Mpz) {
+        self.string(&num.to_str_radix(16));
+    }
+
+    fn value(&mut self, value: &Value) {
+        match *value {
+            Value::Boolvec(ref vec) => {
+                self.u8(0);
+                self.usize(vec.len());
+                for byte in vec.chunks(8) {
+                    self.u8(byte.iter().enumerate().fold(0, |acc, (i, b)| acc | ((*b as u8) << i)));
+                }
+            },
+            Value::Bignum(ref num) => {
+                self.u8(1);
+                self.bignum(num);
+            },
+            Value::Position(ref ptr, rev) => {
+                self.u8(2);
+                self.bool(rev);
+                self.value(ptr);
+            },
+            Value::Pointer {ref pos, ref len, rev} => {
+                self.u8(3);
+                self.bool(rev);
+                self.value(pos);
+                self.value(len);
+            },
+        }
+    }
+
+    fn operand(&mut self, op: &Operand) {
+        match *op {
+            Operand::Const(i) => {
+                self.u8(0);
+                self.usize(i);
+            },
+            Operand::Ptr {pos, len} => {
+                self.u8(1);
+                self.usize(pos);
+                self.usize(len);
+            },
+            Operand::RevPtr {pos, len} => {
+                self.u8(2);
+                self.usize(pos);
+                self.usize(len);
+            },
+            Operand::Value(i) => {
+                self.u8(3);
+                self.usize(i);
+            },
+        }
+    }
+
+    fn optional(&mut self, op: &Option<Operand>) {
+        match *op {
+            Some(ref op) => {
+                self.bool(true);
+                self.operand(op);
+            },
+            None => self.bool(false),
+        }
+    }
+
+    fn code(&mut self, code: &Code) {
+        self.u8(match code.when {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        });
+
+        match code.op {
+            Op::Nop => self.u8(0),
+            Op::Put {ref size, ref value} => {
+                self.u8(1);
+                self.operand(size);
+                self.optional(value);
+            },
+            Op::Pop(ref op) => {
+                self.u8(2);
+                self.operand(op);
+            },
+            Op::Move {ref to, ref from} => {
+                self.u8(3);
+                self.operand(to);
+                self.operand(from);
+            },
+            Op::Arith {op, ref to, ref left, ref right} => {
+                self.u8(4);
+                self.u8(match op {
+                    Arith::Add => 0,
+                    Arith::Sub => 1,
+                    Arith::Mul => 2,
+                    Arith::Div => 3,
+                    Arith::Mod => 4,
+                });
+                self.operand(to);
+                self.operand(left);
+                self.operand(right);
+            },
+            Op::Random {ref to, ref bound} => {
+                self.u8(5);
+                self.operand(to);
+                self.optional(bound);
+            },
+            Op::Logic {op, ref to, ref left, ref right} => {
+                self.u8(6);
+                self.u8(match op {
+                    Logic::And => 0,
+                    Logic::Or => 1,
+                    Logic::Xor => 2,
+                });
+                self.operand(to);
+                self.operand(left);
+                self.operand(right);
+            },
+            Op::Not {ref to, ref from} => {
+                self.u8(7);
+                self.operand(to);
+                self.operand(from);
+            },
+            Op::Shift {towards_end, ref to, ref from, ref amount} => {
+                self.u8(8);
+                self.bool(towards_end);
+                self.operand(to);
+                self.operand(from);
+                self.operand(amount);
+            },
+            Op::Compare {op, ref a, ref b} => {
+                self.u8(9);
+                self.u8(match op {
+                    Compare::Equal => 0,
+                    Compare::Less => 1,
+                    Compare::Greater => 2,
+                });
+                self.operand(a);
+                self.operand(b);
+            },
+            Op::Zero(ref op) => {
+                self.u8(10);
+                self.operand(op);
+            },
+            Op::Call(index) => {
+                self.u8(11);
+                self.usize(index);
+            },
+            Op::Return => self.u8(12),
+            Op::Jump(index) => {
+                self.u8(13);
+                self.usize(index);
+            },
+            Op::JumpIf(index) => {
+                self.u8(14);
+                self.usize(index);
+            },
+            Op::JumpUnless(index) => {
+                self.u8(15);
+                self.usize(index);
+            },
+            Op::Extern {call, value} => {
+                self.u8(16);
+                self.usize(call);
+                self.usize(value);
+            },
+        }
+    }
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ErrorType> {
+        if len > self.bytes.len() - self.pos {
+            return Err(invalid("it ends too early"))
+        }
+        self.pos += len;
+        Ok(&self.bytes[self.pos - len .. self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8, ErrorType> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, ErrorType> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("a flag is neither 0 nor 1")),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, ErrorType> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, ErrorType> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn usize(&mut self) -> Result<usize, ErrorType> {
+        let val = self.u64()?;
+        match val <= usize::max_value() as u64 {
+            true  => Ok(val as usize),
+            false => Err(invalid("a number is too large")),
+        }
+    }
+
+    // A count of things that follow, each at least a byte long, so that a
+    // damaged count can't make the reader allocate without bound.
+    fn len(&mut self) -> Result<usize, ErrorType> {
+        let len = self.usize()?;
+        match len <= self.bytes.len() - self.pos {
+            true  => Ok(len),
+            false => Err(invalid("it ends too early")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ErrorType> {
+        let len = self.len()?;
+        match String::from_utf8(self.take(len)?.to_vec()) {
+            Ok(val) => Ok(val),
+            Err(_) => Err(invalid("a string isn't utf-8")),
+        }
+    }
+
+    fn span(&mut self) -> Result<Span, ErrorType> {
+        let file = match self.bool()? {
+            true  => Some(self.string()?),
+            false => None,
+        };
+
+        Ok(Span {
+            file: file,
+            line: self.usize()?,
+            column: self.usize()?,
+            text: self.string()?,
+        })
+    }
+
+    fn bignum(&mut self) -> Result<Mpz, ErrorType> {
+        let text = self.string()?;
+        let digits = text.trim_start_matches('-');
+
+        // gmp panics on some malformed numbers rather than failing
+        if digits == "" || !digits.chars().all(|c| c.is_digit(16)) {
+            return Err(invalid("a number is malformed"))
+        }
+
+        match Mpz::from_str_radix(&text, 16) {
+            Ok(num) => Ok(num),
+            Err(_) => Err(invalid("a number is malformed")),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, ErrorType> {
+        if depth >= VALUE_DEPTH {
+            return Err(invalid("a value is nested too deeply"))
+        }
+
+        Ok(match self.u8()? {
+            0 => {
+                // a count of bits rather than bytes, bounded by 'take'
+                let len = self.usize()?;
+                let size = match len.checked_add(7) {
+                    Some(bits) => bits / 8,
+                    None => return Err(invalid("a number is too large")),
+                };
+                let bytes = self.take(size)?;
+                Value::Boolvec((0 .. len).map(|i| bytes[i / 8] & (1 << (i % 8)) != 0).collect())
+            },
+            1 => Value::Bignum(self.bignum()?),
+            2 => {
+                let rev = self.bool()?;
+                Value::Position(Box::new(self.value(depth + 1)?), rev)
+            },
+            3 => {
+                let rev = self.bool()?;
+                Value::Pointer {
+                    pos: Box::new(self.value(depth + 1)?),
+                    len: Box::new(self.value(depth + 1)?),
+                    rev: rev,
+                }
+            },
+            _ => return Err(invalid("a value has an unknown kind")),
+        })
+    }
+
+    fn operand(&mut self) -> Result<Operand, ErrorType> {
+        Ok(match self.u8()? {
+            0 => Operand::Const(self.usize()?),
+            1 => Operand::Ptr {
+                pos: self.usize()?,
+                len: self.usize()?,
+            },
+            2 => Operand::RevPtr {
+                pos: self.usize()?,
+                len: self.usize()?,
+            },
+            3 => Operand::Value(self.usize()?),
+            _ => return Err(invalid("an operand has an unknown kind")),
+        })
+    }
+
+    fn optional(&mut self) -> Result<Option<Operand>, ErrorType> {
+        Ok(match self.bool()? {
+            true  => Some(self.operand()?),
+            false => None,
+        })
+    }
+
+    fn code(&mut self) -> Result<Code, ErrorType> {
+        let when = match self.u8()? {
+            0 => None,
+            1 => Some(false),
+            2 => Some(true),
+            _ => return Err(invalid("an instruction has an unknown prefix")),
+        };
+
+        let op = match self.u8()? {
+            0 => Op::Nop,
+            1 => Op::Put {
+                size: self.operand()?,
+                value: self.optional()?,
+            },
+            2 => Op::Pop(self.operand()?),
+            3 => Op::Move {
+                to: self.operand()?,
+                from: self.operand()?,
+            },
+            4 => Op::Arith {
+                op: match self.u8()? {
+                    0 => Arith::Add,
+                    1 => Arith::Sub,
+                    2 => Arith::Mul,
+                    3 => Arith::Div,
+                    4 => Arith::Mod,
+                    _ => return Err(invalid("an arithmetic instruction is unknown")),
+                },
+                to: self.operand()?,
+                left: self.operand()?,
+                right: self.operand()?,
+            },
+            5 => Op::Random {
+                to: self.operand()?,
+                bound: self.optional()?,
+            },
+            6 => Op::Logic {
+                op: match self.u8()? {
+                    0 => Logic::And,
+                    1 => Logic::Or,
+                    2 => Logic::Xor,
+                    _ => return Err(invalid("a logic instruction is unknown")),
+                },
+                to: self.operand()?,
+                left: self.operand()?,
+                right: self.operand()?,
+            },
+            7 => Op::Not {
+                to: self.operand()?,
+                from: self.operand()?,
+            },
+            8 => Op::Shift {
+                towards_end: self.bool()?,
+                to: self.operand()?,
+                from: self.operand()?,
+                amount: self.operand()?,
+            },
+            9 => Op::Compare {
+                op: match self.u8()? {
+                    0 => Compare::Equal,
+                    1 => Compare::Less,
+                    2 => Compare::Greater,
+                    _ => return Err(invalid("a comparison is unknown")),
+                },
+                a: self.operand()?,
+                b: self.operand()?,
+            },
+            10 => Op::Zero(self.operand()?),
+            11 => Op::Call(self.usize()?),
+            12 => Op::Return,
+            13 => Op::Jump(self.usize()?),
+            14 => Op::JumpIf(self.usize()?),
+            15 => Op::JumpUnless(self.usize()?),
+            16 => Op::Extern {
+                call: self.usize()?,
+                value: self.usize()?,
+            },
+            _ => return Err(invalid("an instruction is unknown")),
+        };
+
+        Ok(Code {
+            when: when,
+            op: op,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An image with no code and the given bytes as its only value.
+    fn with_value(value: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        for _ in 0 .. 4 {
+            bytes.extend_from_slice(&0u64.to_le_bytes());
+        }
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(value);
+        for _ in 0 .. 2 {
+            bytes.extend_from_slice(&0u64.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn bignum(text: &str) -> Vec<u8> {
+        let mut bytes = vec![1];
+        bytes.extend_from_slice(&(text.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(text.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let mut program = Program::new();
+        program.values.push(Value::Boolvec(vec![true, false, true]));
+        program.values.push(Value::Position(Box::new(Value::Bignum(Mpz::from_str_radix("300", 10).unwrap())), true));
+
+        let image = Image {
+            spans: vec![],
+            sources: vec![],
+            labels: vec![],
+            defines: vec![("A".to_string(), "3".to_string())],
+            program: program,
+        };
+
+        let read = Image::read(&image.write()).unwrap();
+        assert_eq!(read.defines, image.defines);
+        let shown = |values: &[Value]| values.iter().map(|v| format!("{}", v)).collect::<Vec<_>>();
+        assert_eq!(shown(&read.program.values), shown(&image.program.values));
+    }
+
+    #[test]
+    fn refuses_truncated_images() {
+        let bytes = with_value(&bignum("ff"));
+        assert!(Image::read(&bytes).is_ok());
+
+        for len in 0 .. bytes.len() {
+            assert!(Image::read(&bytes[.. len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_lengths() {
+        let mut boolvec = vec![0];
+        boolvec.extend_from_slice(&u64::max_value().to_le_bytes());
+        assert!(Image::read(&with_value(&boolvec)).is_err());
+
+        let mut boolvec = vec![0];
+        boolvec.extend_from_slice(&1000u64.to_le_bytes());
+        boolvec.push(0xff);
+        assert!(Image::read(&with_value(&boolvec)).is_err());
+
+        let mut string = vec![1];
+        string.extend_from_slice(&u64::max_value().to_le_bytes());
+        assert!(Image::read(&with_value(&string)).is_err());
+
+        let mut count = MAGIC.to_vec();
+        count.extend_from_slice(&VERSION.to_le_bytes());
+        count.extend_from_slice(&u64::max_value().to_le_bytes());
+        assert!(Image::read(&count).is_err());
+    }
+
+    #[test]
+    fn refuses_deeply_nested_values() {
+        let nested = |depth: usize| {
+            let mut value = vec![];
+            for _ in 0 .. depth {
+                value.extend_from_slice(&[2, 0]);
+            }
+            value.extend(bignum("1"));
+            with_value(&value)
+        };
+
+        assert!(Image::read(&nested(VALUE_DEPTH - 1)).is_ok());
+        assert!(Image::read(&nested(VALUE_DEPTH)).is_err());
+        assert!(Image::read(&nested(200000)).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use super::super::value::Value;
+use super::super::tater::Tater;
+use super::super::bytecode::{Program, Code, Op, Compare};
+use super::super::error::*;
+
+use super::Instruction;
+
+// Two operands compared as unsigned numbers.
+struct Pair {
+    a: Value,
+    b: Value,
+}
+
+// Comparisons store their outcome in 'Garden::validity', which the
+// conditional jumps then act upon.
+pub struct Equal(Pair);
+pub struct Less(Pair);
+pub struct Greater(Pair);
+pub struct Zero(Value);
+
+impl Pair {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Pair, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(2))?;
+
+        Ok(Pair {
+            a: Value::new(args[0], err, false)?,
+            b: Value::new(args[1], err, false)?,
+        })
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        vec![&self.a, &self.b]
+    }
+
+    fn lower(&self, program: &mut Program, op: Compare) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Compare {
+            op: op,
+            a: program.operand(&self.a),
+            b: program.operand(&self.b),
+        }))
+    }
+}
+
+impl Instruction for Equal {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Equal(Pair::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Compare::Equal)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "equal"
+    }
+}
+
+impl Instruction for Less {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Less(Pair::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Compare::Less)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "less"
+    }
+}
+
+impl Instruction for Greater {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Greater(Pair::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Compare::Greater)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "greater"
+    }
+}
+
+impl Instruction for Zero {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(1))?;
+
+        Ok(Box::new(Zero(Value::new(args[0], err, false)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Zero(program.operand(&self.0))))
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        vec![&self.0]
+    }
+
+    fn name(&self) -> &str {
+        "zero"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{run, bits, fails_with};
+
+    // Runs 'test' after 'setup' (whose pointers start at 2) and reports
+    // whether it held, checking that jumpif and jumpunless agree: the first
+    // bit is set when jumpunless isn't taken, the second when jumpif isn't.
+    fn holds(setup: &str, test: &str) -> bool {
+        let source = format!(
+            "put 2\n{}\n{}\njumpunless unless\nmove [0; 1]; 1\n@unless\njumpif if\nmove [1; 1]; 1\n@if",
+            setup, test);
+        let garden = run(&source).unwrap();
+
+        let (not_unless, not_if) = (bits(&garden, 0, 1) == "1", bits(&garden, 1, 1) == "1");
+        assert!(not_unless != not_if, "{}", source);
+        not_unless
+    }
+
+    #[test]
+    fn equal() {
+        assert!(holds("put 8; 5\nput 8; 5", "equal [2; 8]; [10; 8]"));
+        assert!(!holds("put 8; 5\nput 8; 6", "equal [2; 8]; [10; 8]"));
+        assert!(holds("put 8; 5", "equal [2; 8]; 5"));
+        assert!(!holds("put 8; 5", "equal 6; [2; 8]"));
+        assert!(holds("", "equal 7; 7"));
+    }
+
+    #[test]
+    fn less_and_greater() {
+        assert!(holds("put 8; 5\nput 8; 6", "less [2; 8]; [10; 8]"));
+        assert!(!holds("put 8; 6\nput 8; 6", "less [2; 8]; [10; 8]"));
+        assert!(!holds("put 8; 7\nput 8; 6", "less [2; 8]; [10; 8]"));
+
+        assert!(holds("put 8; 7\nput 8; 6", "greater [2; 8]; [10; 8]"));
+        assert!(!holds("put 8; 6\nput 8; 6", "greater [2; 8]; [10; 8]"));
+        assert!(!holds("put 8; 5\nput 8; 6", "greater [2; 8]; [10; 8]"));
+
+        // unsigned, so the top bit set is large rather than negative
+        assert!(holds("put 8; 255", "greater [2; 8]; 1"));
+        assert!(holds("put 8; 255", "less 1; [2; 8]"));
+    }
+
+    #[test]
+    fn unequal_widths() {
+        assert!(holds("put 4; 5\nput 70; 5", "equal [2; 4]; [6; 70]"));
+        assert!(holds("put 70; 5\nput 4; 5", "equal [2; 70]; [72; 4]"));
+        assert!(!holds("put 4; 5\nput 70; 590295810358705651717", "equal [2; 4]; [6; 70]"));
+
+        assert!(holds("put 4; 15\nput 70; 16", "less [2; 4]; [6; 70]"));
+        assert!(holds("put 70; 16\nput 4; 15", "greater [2; 70]; [72; 4]"));
+        assert!(!holds("put 70; 15\nput 4; 15", "greater [2; 70]; [72; 4]"));
+    }
+
+    #[test]
+    fn zero() {
+        assert!(holds("put 8", "zero [2; 8]"));
+        assert!(!holds("put 8; 128", "zero [2; 8]"));
+        assert!(holds("put 130", "zero [2; 130]"));
+        assert!(!holds("put 130; 680564733841876926926749214863536422912", "zero [2; 130]"));
+        assert!(holds("", "zero 0"));
+        assert!(!holds("", "zero 3"));
+    }
+
+    #[test]
+    fn arguments() {
+        fails_with("equal 1", "ArgumentError");
+        fails_with("less 1; 2; 3", "ArgumentError");
+        fails_with("zero", "ArgumentError");
+        fails_with("zero 1; 2", "ArgumentError");
+    }
+}
@@ -0,0 +1,148 @@
+use super::super::value::Value;
+use super::super::tater::Tater;
+use super::super::bytecode::{Program, Code, Op};
+use super::super::error::*;
+
+use super::{Instruction, build_instruction};
+
+// Prefixes any instruction so that it only runs when 'Garden::validity'
+// matches the condition:
+//     if <ins> <args>      => runs <ins> when the last comparison held
+//     unless <ins> <args>  => runs <ins> when it did not
+pub struct Predicated {
+    when: bool,
+    ins: Box<Instruction>,
+}
+
+impl Instruction for Predicated {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::AtLeast(1))?;
+
+        let inner_name = match args[0].split_whitespace().next() {
+            Some(val) => val,
+            None => return Err(err.error(ErrorType::Empty(format!("'{}' prefix", name)))),
+        };
+
+        let mut inner_args: Vec<&str> = vec![];
+        let first = args[0][inner_name.len() ..].trim();
+
+        if first != "" {
+            inner_args.push(first);
+        }
+
+        inner_args.extend_from_slice(&args[1 ..]);
+
+        Ok(Box::new(Predicated {
+            when: name == "if",
+            ins: build_instruction(inner_name, &inner_args, err)?,
+        }))
+    }
+
+    // Nested prefixes that contradict each other can never run.
+    fn lower(&self, program: &mut Program, tater: &Tater) -> Result<Code, TaterError> {
+        let mut code = self.ins.lower(program, tater)?;
+
+        match code.when {
+            Some(when) if when != self.when => code.op = Op::Nop,
+            _ => code.when = Some(self.when),
+        }
+        Ok(code)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.ins.operands()
+    }
+
+    fn name(&self) -> &str {
+        match self.when {
+            true  => "if",
+            false => "unless",
+        }
+    }
+
+    fn source(&self) -> String {
+        format!("{} {}", self.name(), self.ins.source())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Predicated;
+    use super::super::Instruction;
+    use super::super::tests::{run, bits, fails_with};
+    use super::super::super::bytecode::{Program, Code, Op};
+    use super::super::super::tater::Tater;
+    use super::super::super::error::Error;
+
+    fn lowered(name: &str, args: &[&str]) -> Code {
+        let err = Error::new(format!("{} {}", name, args.join("; ")), 1, 0, None);
+        let ins = Predicated::new(name, args, &err).unwrap();
+        ins.lower(&mut Program::new(), &Tater::new(false)).unwrap()
+    }
+
+    fn is_nop(code: &Code) -> bool {
+        match code.op {
+            Op::Nop => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn plain_instructions() {
+        let garden = run("put 4\nequal 1; 1\nif move [0; 4]; 9\nunless move [0; 4]; 5").unwrap();
+        assert_eq!(bits(&garden, 0, 4), "9");
+
+        let garden = run("put 4\nequal 1; 2\nif move [0; 4]; 9\nunless move [0; 4]; 5").unwrap();
+        assert_eq!(bits(&garden, 0, 4), "5");
+
+        let code = lowered("unless", &["add [0; 4]", "1"]);
+        assert_eq!(code.when, Some(false));
+        assert!(!is_nop(&code));
+    }
+
+    #[test]
+    fn calls() {
+        let source = "put 4\n{}\nif call nine\nunless call five\njump end\n\
+                      @nine\nmove [0; 4]; 9\nreturn\n@five\nmove [0; 4]; 5\nreturn\n@end";
+
+        let garden = run(&source.replace("{}", "less 1; 2")).unwrap();
+        assert_eq!(bits(&garden, 0, 4), "9");
+
+        let garden = run(&source.replace("{}", "less 2; 1")).unwrap();
+        assert_eq!(bits(&garden, 0, 4), "5");
+    }
+
+    #[test]
+    fn stacked_prefixes() {
+        let code = lowered("if", &["if put 1"]);
+        assert_eq!(code.when, Some(true));
+        assert!(!is_nop(&code));
+
+        let code = lowered("unless", &["unless unless put 1"]);
+        assert_eq!(code.when, Some(false));
+        assert!(!is_nop(&code));
+
+        let garden = run("put 4\nzero 0\nif if move [0; 4]; 9\nunless unless move [0; 4]; 5").unwrap();
+        assert_eq!(bits(&garden, 0, 4), "9");
+    }
+
+    #[test]
+    fn contradictory_prefixes() {
+        assert!(is_nop(&lowered("if", &["unless put 1"])));
+        assert!(is_nop(&lowered("unless", &["if put 1"])));
+        assert!(is_nop(&lowered("if", &["if unless put 1"])));
+
+        for test in &["zero 0", "zero 1"] {
+            let garden = run(&format!("put 4\n{}\nif unless move [0; 4]; 9\nunless if call end\n@end", test))
+                .unwrap();
+            assert_eq!(bits(&garden, 0, 4), "0");
+        }
+    }
+
+    #[test]
+    fn arguments() {
+        fails_with("if", "ArgumentError");
+        fails_with("unless put", "ArgumentError");
+        fails_with("if frobnicate 1", "NonExistent");
+    }
+}
@@ -0,0 +1,350 @@
+use super::super::value::Value;
+use super::super::tater::Tater;
+use super::super::bytecode::{Program, Code, Op, Logic};
+use super::super::error::*;
+
+use super::Instruction;
+
+// Bitwise instructions work on the bits of their operands directly:
+//     <ins> to; a     => to = to <op> a
+//     <ins> to; a; b  => to = a <op> b
+// Operands narrower than 'to' are zero extended, wider ones are truncated.
+struct Bitwise {
+    to: Value,
+    left: Option<Value>,
+    right: Value,
+}
+
+// Shifts move bits towards the end ('left') or the start ('right') of 'to':
+//     <ins> to; n     => to = to <op> n
+//     <ins> to; a; n  => to = a <op> n
+struct Shift {
+    to: Value,
+    from: Option<Value>,
+    amount: Value,
+}
+
+pub struct And(Bitwise);
+pub struct Or(Bitwise);
+pub struct Xor(Bitwise);
+pub struct Left(Shift);
+pub struct Right(Shift);
+
+pub struct Not {
+    to: Value,
+    from: Option<Value>,
+}
+
+impl Bitwise {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Bitwise, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Range(2, 3))?;
+
+        let (left, right) = match args.len() == 3 {
+            true  => (Some(Value::new(args[1], err, false)?), Value::new(args[2], err, false)?),
+            false => (None, Value::new(args[1], err, false)?),
+        };
+
+        Ok(Bitwise {
+            to: Value::new(args[0], err, true)?,
+            left: left,
+            right: right,
+        })
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        let mut ret = vec![&self.to];
+        ret.extend(self.left.as_ref());
+        ret.push(&self.right);
+        ret
+    }
+
+    fn lower(&self, program: &mut Program, op: Logic) -> Result<Code, TaterError> {
+        let to = program.operand(&self.to);
+
+        Ok(Code::from(Op::Logic {
+            op: op,
+            to: to,
+            left: match self.left {
+                Some(ref val) => program.operand(val),
+                None => to,
+            },
+            right: program.operand(&self.right),
+        }))
+    }
+}
+
+impl Shift {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Shift, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Range(2, 3))?;
+
+        let (from, amount) = match args.len() == 3 {
+            true  => (Some(Value::new(args[1], err, false)?), Value::new(args[2], err, false)?),
+            false => (None, Value::new(args[1], err, false)?),
+        };
+
+        Ok(Shift {
+            to: Value::new(args[0], err, true)?,
+            from: from,
+            amount: amount,
+        })
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        let mut ret = vec![&self.to];
+        ret.extend(self.from.as_ref());
+        ret.push(&self.amount);
+        ret
+    }
+
+    fn lower(&self, program: &mut Program, towards_end: bool) -> Result<Code, TaterError> {
+        let to = program.operand(&self.to);
+
+        Ok(Code::from(Op::Shift {
+            towards_end: towards_end,
+            to: to,
+            from: match self.from {
+                Some(ref val) => program.operand(val),
+                None => to,
+            },
+            amount: program.operand(&self.amount),
+        }))
+    }
+}
+
+impl Instruction for And {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(And(Bitwise::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Logic::And)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "and"
+    }
+}
+
+impl Instruction for Or {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Or(Bitwise::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Logic::Or)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "or"
+    }
+}
+
+impl Instruction for Xor {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Xor(Bitwise::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Logic::Xor)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "xor"
+    }
+}
+
+impl Instruction for Not {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Range(1, 2))?;
+
+        let from = match args.len() == 2 {
+            true  => Some(Value::new(args[1], err, false)?),
+            false => None,
+        };
+
+        Ok(Box::new(Not {
+            to: Value::new(args[0], err, true)?,
+            from: from,
+        }))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        let to = program.operand(&self.to);
+
+        Ok(Code::from(Op::Not {
+            to: to,
+            from: match self.from {
+                Some(ref val) => program.operand(val),
+                None => to,
+            },
+        }))
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        let mut ret = vec![&self.to];
+        ret.extend(self.from.as_ref());
+        ret
+    }
+
+    fn name(&self) -> &str {
+        "not"
+    }
+}
+
+impl Instruction for Left {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Left(Shift::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, true)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "left"
+    }
+}
+
+impl Instruction for Right {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Right(Shift::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, false)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "right"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate gmp;
+
+    use super::super::tests::{run, bits, fails_with};
+
+    use self::gmp::mpz::Mpz;
+
+    #[test]
+    fn narrower_operands_are_zero_extended() {
+        let garden = run("put 4; 11\nput 12; 3855\n\
+                          put 16\nand [16; 16]; [0; 4]; [4; 12]\n\
+                          put 16\nor [32; 16]; [0; 4]; [4; 12]\n\
+                          put 16\nxor [48; 16]; [0; 4]; [4; 12]").unwrap();
+        assert_eq!(bits(&garden, 16, 16), "11");
+        assert_eq!(bits(&garden, 32, 16), "3855");
+        assert_eq!(bits(&garden, 48, 16), "3844");
+    }
+
+    #[test]
+    fn wider_operands_are_truncated() {
+        let garden = run("put 16; 43981\nput 4; 15\n\
+                          put 8\nand [20; 8]; [0; 16]; [16; 4]\n\
+                          put 8\nor [28; 8]; [0; 16]; [16; 4]\n\
+                          put 8; 255\nxor [36; 8]; [0; 16]").unwrap();
+        assert_eq!(bits(&garden, 20, 8), "13");
+        assert_eq!(bits(&garden, 28, 8), "207");
+        assert_eq!(bits(&garden, 36, 8), "50");
+    }
+
+    #[test]
+    fn across_words() {
+        let garden = run("put 3\nput 130; 1361129467683753853853498429727072845823\n\
+                          xor [3; 130]; 1\nput 70; 1180591620717411303423\nand [133; 70]; [3; 130]").unwrap();
+        assert_eq!(bits(&garden, 3, 130), "1361129467683753853853498429727072845822");
+        assert_eq!(bits(&garden, 133, 70), "1180591620717411303422");
+    }
+
+    #[test]
+    fn not() {
+        let garden = run("put 8; 5\nnot [0; 8]\nput 4; 11\nput 16\nnot [12; 16]; [8; 4]").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "250");
+        assert_eq!(bits(&garden, 12, 16), "65524");
+    }
+
+    #[test]
+    fn shifts() {
+        let garden = run("put 8; 1\nleft [0; 8]; 3\n\
+                          put 16; 43981\nput 8\nright [24; 8]; [8; 16]; 4\n\
+                          put 4; 11\nput 16\nleft [36; 16]; [32; 4]; 14\n\
+                          put 130; 1\nleft [52; 130]; 65\nput 130\nright [182; 130]; [52; 130]; 1").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "8");
+        assert_eq!(bits(&garden, 24, 8), "188");
+        assert_eq!(bits(&garden, 36, 16), "49152");
+        assert_eq!(bits(&garden, 52, 130), "36893488147419103232");
+        assert_eq!(bits(&garden, 182, 130), "18446744073709551616");
+    }
+
+    #[test]
+    fn shifting_by_the_width_or_more_clears() {
+        let garden = run("put 8; 255\nleft [0; 8]; 8\nput 8; 255\nright [8; 8]; 9\n\
+                          put 64; 5\nleft [16; 64]; 64\nput 65; 5\nright [80; 65]; 1000").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "0");
+        assert_eq!(bits(&garden, 8, 8), "0");
+        assert_eq!(bits(&garden, 16, 64), "0");
+        assert_eq!(bits(&garden, 80, 65), "0");
+    }
+
+    // Shifts of every width against the same shifts done with 'Mpz'.
+    #[test]
+    fn shifts_match_numbers() {
+        let widths = [1, 7, 63, 64, 65, 127, 130];
+        let amounts = [0, 1, 5, 63, 64, 65, 100, 129];
+        let mut state: u64 = 3;
+
+        for &to in &widths {
+            for &from in &widths {
+                let mut source = Mpz::zero();
+                for _ in 0 .. 3 {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    source = (source << 64) + Mpz::from(state);
+                }
+                source = source.modulus(&(Mpz::one() << from));
+
+                for &amount in &amounts {
+                    for &(name, towards_end) in &[("left", true), ("right", false)] {
+                        let program = format!("put {}; {}\nput {}\n{} [{}; {}]; [0; {}]; {}",
+                            from, source, to, name, from, to, from, amount);
+                        let garden = run(&program).unwrap();
+
+                        let shifted = match towards_end {
+                            true  => &source << amount,
+                            false => &source >> amount,
+                        };
+                        let expected = shifted.modulus(&(Mpz::one() << to));
+                        assert_eq!(bits(&garden, from, to), expected.to_string(), "{}", program);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn arguments() {
+        fails_with("put 8\nand [0; 8]", "ArgumentError");
+        fails_with("put 8\nnot [0; 8]; 1; 2", "ArgumentError");
+        fails_with("left 1; 2", "InvalidPointer");
+    }
+}
@@ -0,0 +1,260 @@
+use super::super::value::Value;
+use super::super::tater::Tater;
+use super::super::bytecode::{Program, Code, Op, Arith};
+use super::super::error::*;
+
+use super::Instruction;
+
+// Shared operands of every arithmetic instruction:
+//     <ins> to; a     => to = to <op> a
+//     <ins> to; a; b  => to = a <op> b
+// Results are wrapped to the width of 'to', so overflow and negative
+// results behave like unsigned fixed-width integers.
+struct Operation {
+    to: Value,
+    left: Option<Value>,
+    right: Value,
+}
+
+pub struct Add(Operation);
+pub struct Sub(Operation);
+pub struct Mul(Operation);
+pub struct Div(Operation);
+pub struct Mod(Operation);
+
+// Fills 'to' with uniformly random bits, or a number below 'bound':
+//     random to
+//     random to; bound
+pub struct Random {
+    to: Value,
+    bound: Option<Value>,
+}
+
+impl Operation {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Operation, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Range(2, 3))?;
+
+        let (left, right) = match args.len() == 3 {
+            true  => (Some(Value::new(args[1], err, false)?), Value::new(args[2], err, false)?),
+            false => (None, Value::new(args[1], err, false)?),
+        };
+
+        Ok(Operation {
+            to: Value::new(args[0], err, true)?,
+            left: left,
+            right: right,
+        })
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        let mut ret = vec![&self.to];
+        ret.extend(self.left.as_ref());
+        ret.push(&self.right);
+        ret
+    }
+
+    fn lower(&self, program: &mut Program, op: Arith) -> Result<Code, TaterError> {
+        let to = program.operand(&self.to);
+
+        Ok(Code::from(Op::Arith {
+            op: op,
+            to: to,
+            left: match self.left {
+                Some(ref val) => program.operand(val),
+                None => to,
+            },
+            right: program.operand(&self.right),
+        }))
+    }
+}
+
+impl Instruction for Add {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Add(Operation::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Arith::Add)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "add"
+    }
+}
+
+impl Instruction for Sub {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Sub(Operation::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Arith::Sub)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "sub"
+    }
+}
+
+impl Instruction for Mul {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Mul(Operation::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Arith::Mul)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "mul"
+    }
+}
+
+impl Instruction for Div {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Div(Operation::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Arith::Div)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "div"
+    }
+}
+
+impl Instruction for Mod {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        Ok(Box::new(Mod(Operation::new(name, args, err)?)))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        self.0.lower(program, Arith::Mod)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        self.0.operands()
+    }
+
+    fn name(&self) -> &str {
+        "mod"
+    }
+}
+
+impl Instruction for Random {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Range(1, 2))?;
+
+        let bound = match args.len() == 2 {
+            true  => Some(Value::new(args[1], err, false)?),
+            false => None,
+        };
+
+        Ok(Box::new(Random {
+            to: Value::new(args[0], err, true)?,
+            bound: bound,
+        }))
+    }
+
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Random {
+            to: program.operand(&self.to),
+            bound: self.bound.as_ref().map(|val| program.operand(val)),
+        }))
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        let mut ret = vec![&self.to];
+        ret.extend(self.bound.as_ref());
+        ret
+    }
+
+    fn name(&self) -> &str {
+        "random"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{run, bits, fails_with};
+
+    #[test]
+    fn two_operands() {
+        let garden = run("put 8; 7\nadd [0; 8]; 5\nput 8; 9\nsub [8; 8]; 4").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "12");
+        assert_eq!(bits(&garden, 8, 8), "5");
+    }
+
+    #[test]
+    fn three_operands() {
+        let garden = run("put 8\nput 8; 6\nput 8; 7\nmul [0; 8]; [8; 8]; [16; 8]\nsub [8; 8]; 20; 13").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "42");
+        assert_eq!(bits(&garden, 8, 8), "7");
+        assert_eq!(bits(&garden, 16, 8), "7");
+    }
+
+    #[test]
+    fn wraps_to_the_width_of_to() {
+        let garden = run("put 8; 250\nadd [0; 8]; 10\nput 4; 15\nmul [8; 4]; 3\nput 70; 1\nadd [12; 70]; 1180591620717411303423")
+            .unwrap();
+        assert_eq!(bits(&garden, 0, 8), "4");
+        assert_eq!(bits(&garden, 8, 4), "13");
+        assert_eq!(bits(&garden, 12, 70), "0");
+    }
+
+    #[test]
+    fn negative_results_wrap_around() {
+        let garden = run("put 8; 3\nsub [0; 8]; 5\nput 65\nsub [8; 65]; 1\nput 8\nsub [73; 8]; 1; 2").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "254");
+        assert_eq!(bits(&garden, 8, 65), "36893488147419103231");
+        assert_eq!(bits(&garden, 73, 8), "255");
+    }
+
+    #[test]
+    fn division() {
+        let garden = run("put 8; 17\ndiv [0; 8]; 5\nput 8; 17\nmod [8; 8]; 5\nput 8\ndiv [16; 8]; 100; 7").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "3");
+        assert_eq!(bits(&garden, 8, 8), "2");
+        assert_eq!(bits(&garden, 16, 8), "14");
+    }
+
+    #[test]
+    fn division_by_zero() {
+        fails_with("put 8; 1\ndiv [0; 8]; 0", "DivisionByZero");
+        fails_with("put 8; 1\nmod [0; 8]; 0", "DivisionByZero");
+        fails_with("put 8; 1\nput 8\ndiv [0; 8]; [8; 8]", "DivisionByZero");
+    }
+
+    #[test]
+    fn random() {
+        let garden = run("put 8\nrandom [0; 8]; 10\nput 3\nrandom [8; 3]").unwrap();
+        assert!(bits(&garden, 0, 8).parse::<u32>().unwrap() < 10);
+        assert!(bits(&garden, 8, 3).parse::<u32>().unwrap() < 8);
+
+        fails_with("put 8\nrandom [0; 8]; 0", "InvalidValue");
+    }
+
+    #[test]
+    fn arguments() {
+        fails_with("put 8\nadd [0; 8]", "ArgumentError");
+        fails_with("put 8\nadd [0; 8]; 1; 2; 3", "ArgumentError");
+        fails_with("add 1; 2", "InvalidPointer");
+    }
+}
@@ -1,6 +1,6 @@
 use super::super::value::Value;
-use super::super::garden::Garden;
 use super::super::tater::Tater;
+use super::super::bytecode::{Program, Code, Op};
 use super::super::error::*;
 
 use super::Instruction;
@@ -14,65 +14,77 @@ pub struct Move {
 }
 
 impl Instruction for Put {
-    fn new(name: &str, args: &[&str], err: &Error) -> Box<Instruction> {
-        err.check_args("instruction", name, args.len(), ArgumentType::Range(1, 2));
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Range(1, 2))?;
 
         let val = match args.len() >= 2 {
-            true  => Some(Value::new(args[1], err, false)),
+            true  => Some(Value::new(args[1], err, false)?),
             false => None,
         };
 
-        Box::new(Put(Value::new(args[0], err, false), val))
+        Ok(Box::new(Put(Value::new(args[0], err, false)?, val)))
     }
 
-    fn exec(&self, garden: &mut Garden, _: &Tater) {
-        let size = self.0.get_usize(garden);
-        let pos = garden.stack_len();
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Put {
+            size: program.operand(&self.0),
+            value: self.1.as_ref().map(|val| program.operand(val)),
+        }))
+    }
 
-        garden.push(size, false);
+    fn operands(&self) -> Vec<&Value> {
+        let mut ret = vec![&self.0];
+        ret.extend(self.1.as_ref());
+        ret
+    }
 
-        match self.1 {
-            Some(ref val) => {
-                let num = val.get_bignum(garden);
-                garden.set_bits_bignum(&num, pos, size);
-            },
-            None => {}
-        }
+    fn name(&self) -> &str {
+        "put"
     }
 }
 
 impl Instruction for Pop {
-    fn new(name: &str, args: &[&str], err: &Error) -> Box<Instruction> {
-        err.check_args("instruction", name, args.len(), ArgumentType::Exact(1));
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(1))?;
+
+        Ok(Box::new(Pop(Value::new(args[0], err, false)?)))
+    }
 
-        Box::new(Pop(Value::new(args[0], err, false)))
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Pop(program.operand(&self.0))))
     }
 
-    fn exec(&self, garden: &mut Garden, _: &Tater) {
-        let size = self.0.get_usize(garden);
-        garden.pop(size);
+    fn operands(&self) -> Vec<&Value> {
+        vec![&self.0]
+    }
+
+    fn name(&self) -> &str {
+        "pop"
     }
 }
 
 impl Instruction for Move {
-    fn new(name: &str, args: &[&str], err: &Error) -> Box<Instruction> {
-        err.check_args("instruction", name, args.len(), ArgumentType::Exact(2));
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(2))?;
 
-        Box::new(Move {
-            to: Value::new(args[0], err, true),
-            from: Value::new(args[1], err, false),
-        })
+        Ok(Box::new(Move {
+            to: Value::new(args[0], err, true)?,
+            from: Value::new(args[1], err, false)?,
+        }))
     }
 
-    fn exec(&self, garden: &mut Garden, _: &Tater) {
-        if !self.from.can_coerce(self.to.get_size(garden), garden) {
-            panic!("Argument bigger than assignment!");
-        }
+    fn lower(&self, program: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Move {
+            to: program.operand(&self.to),
+            from: program.operand(&self.from),
+        }))
+    }
 
-        let pos = self.to.get_ptr_position(&garden);
-        let val = self.from.get_bignum(&garden);
-        let size = self.to.get_size(garden);
+    fn operands(&self) -> Vec<&Value> {
+        vec![&self.to, &self.from]
+    }
 
-        garden.set_bits_bignum(&val, pos, size);
+    fn name(&self) -> &str {
+        "move"
     }
 }
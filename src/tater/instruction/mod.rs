@@ -1,20 +1,61 @@
-use super::garden::Garden;
+use super::value::Value;
 use super::tater::Tater;
+use super::bytecode::{Program, Code};
 use super::error::*;
 
 mod mem;
 mod sys;
+mod math;
+mod logic;
+mod cmp;
+mod cond;
 
+// Instructions are only the parsed form of a line; 'lower' turns each into
+// the 'Code' that 'Tater::run' executes, resolving its label and extern
+// references and adding its operands to the pools of 'program'.
 pub trait Instruction {
-    fn new(name: &str, arguments: &[&str], err: &Error) -> Box<Instruction> where Self:Sized;
-    fn exec(&self, env: &mut Garden, tater: &Tater);
+    fn new(name: &str, arguments: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> where Self:Sized;
+    fn lower(&self, program: &mut Program, tater: &Tater) -> Result<Code, TaterError>;
+
+    // The values the instruction was written with, in order.
+    fn operands(&self) -> Vec<&Value> {
+        vec![]
+    }
+
+    // The name the instruction is written with.
+    fn name(&self) -> &str;
+
+    // The instruction written as canonical source, which parses back to the
+    // same instruction.
+    fn source(&self) -> String {
+        let operands: Vec<String> = self.operands().iter().map(|val| format!("{}", val)).collect();
+        match operands.len() {
+            0 => self.name().to_string(),
+            _ => format!("{} {}", self.name(), operands.join("; ")),
+        }
+    }
 }
 
-pub fn create_instruction(name: &str, arguments: &[&str], tater: &Tater, err: &Error) -> Box<Instruction> {
+pub fn create_instruction(name: &str, arguments: &[&str], tater: &Tater, err: &Error) -> Result<Box<Instruction>, TaterError> {
     if tater.print_parsed {
-        println!("{}: {}", name, arguments.join(", "));
+        println!("{}: {}: {}", err.span(), name, arguments.join(", "));
     }
 
+    build_instruction(name, arguments, err)
+}
+
+// Every name 'build_instruction' knows.
+pub const INSTRUCTIONS: [&'static str; 27] = [
+    "put", "pop", "move",
+    "call", "return", "extern",
+    "jump", "jumpif", "jumpunless",
+    "equal", "less", "greater", "zero",
+    "if", "unless",
+    "and", "or", "xor", "not", "left", "right",
+    "add", "sub", "mul", "div", "mod", "random",
+];
+
+pub fn build_instruction(name: &str, arguments: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
     match name {
         "put" => mem::Put::new(name, arguments, err),
         "pop" => mem::Pop::new(name, arguments, err),
@@ -24,11 +65,22 @@ pub fn create_instruction(name: &str, arguments: &[&str], tater: &Tater, err: &E
         "return" => sys::Return::new(name, arguments, err),
         "extern" => sys::Extern::new(name, arguments, err),
 
-        /*
+        "jump" => sys::Jump::new(name, arguments, err),
+        "jumpif" => sys::JumpIf::new(name, arguments, err),
+        "jumpunless" => sys::JumpUnless::new(name, arguments, err),
+
+        "equal" => cmp::Equal::new(name, arguments, err),
+        "less" => cmp::Less::new(name, arguments, err),
+        "greater" => cmp::Greater::new(name, arguments, err),
+        "zero" => cmp::Zero::new(name, arguments, err),
+
+        "if" => cond::Predicated::new(name, arguments, err),
+        "unless" => cond::Predicated::new(name, arguments, err),
+
         "and" => logic::And::new(name, arguments, err),
         "or"  => logic::Or::new(name, arguments, err),
         "xor" => logic::Xor::new(name, arguments, err),
-        "nor" => logic::Not::new(name, arguments, err),
+        "not" => logic::Not::new(name, arguments, err),
         "left"  => logic::Left::new(name, arguments, err),
         "right" => logic::Right::new(name, arguments, err),
 
@@ -36,11 +88,51 @@ pub fn create_instruction(name: &str, arguments: &[&str], tater: &Tater, err: &E
         "sub" => math::Sub::new(name, arguments, err),
         "mul" => math::Mul::new(name, arguments, err),
         "div" => math::Div::new(name, arguments, err),
-        "mod" => math::Mod::new(name, arguments, err),*/
+        "mod" => math::Mod::new(name, arguments, err),
+        "random" => math::Random::new(name, arguments, err),
 
-        n => err.throw(ErrorType::NonExistent {
+        n => Err(err.error(ErrorType::NonExistent {
             type_name: "instruction".to_string(),
             value: n.to_string(),
-        })
+        }))
+    }
+}
+
+// Helpers for the tests of each kind of instruction.
+#[cfg(test)]
+pub mod tests {
+    extern crate gmp;
+
+    use super::super::tater::Tater;
+    use super::super::garden::Garden;
+    use super::super::error::*;
+
+    use self::gmp::mpz::Mpz;
+
+    // Parses and runs 'source' on a new garden.
+    pub fn run(source: &str) -> Result<Garden, TaterError> {
+        let mut tater = Tater::new(false);
+        for (i, line) in source.lines().enumerate() {
+            tater.parse_line(&line.to_string(), i + 1, None)?;
+        }
+
+        let mut garden = Garden::with_seed(0);
+        tater.run(&mut garden)?;
+        Ok(garden)
+    }
+
+    // The 'len' bits at 'pos' read as an unsigned number, in decimal.
+    pub fn bits(garden: &Garden, pos: usize, len: usize) -> String {
+        let mut num = Mpz::new();
+        garden.load_bignum(pos, len, &mut num).unwrap();
+        num.to_string()
+    }
+
+    // Fails unless 'source' fails with the kind of error named 'kind'.
+    pub fn fails_with(source: &str, kind: &str) {
+        match run(source) {
+            Ok(_) => panic!("'{}' ran", source),
+            Err(e) => assert!(format!("{:?}", e.kind).starts_with(kind), "'{}' failed with {}", source, e),
+        }
     }
 }
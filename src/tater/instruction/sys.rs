@@ -1,8 +1,8 @@
 extern crate gmp;
 
 use super::super::value::Value;
-use super::super::garden::Garden;
 use super::super::tater::Tater;
+use super::super::bytecode::{Program, Code, Op};
 use super::super::error::*;
 
 use super::Instruction;
@@ -10,54 +10,142 @@ use super::Instruction;
 pub struct Return;
 pub struct Call(String);
 
+pub struct Jump(String);
+pub struct JumpIf(String);
+pub struct JumpUnless(String);
+
 pub struct Extern {
     name: String,
     val: Value,
 }
 
 impl Instruction for Return {
-    fn new(name: &str, args: &[&str], err: &Error) -> Box<Instruction> {
-        err.check_args("instruction", name, args.len(), ArgumentType::Exact(0));
-        Box::new(Return)
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(0))?;
+        Ok(Box::new(Return))
+    }
+
+    fn lower(&self, _: &mut Program, _: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Return))
     }
 
-    fn exec(&self, garden: &mut Garden, _: &Tater) {
-        garden.ret();
+    fn name(&self) -> &str {
+        "return"
     }
 }
 
 impl Instruction for Call {
-    fn new(name: &str, args: &[&str], err: &Error) -> Box<Instruction> {
-        err.check_args("instruction", name, args.len(), ArgumentType::Exact(1));
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(1))?;
+
+        Ok(Box::new(Call(args[0].to_string())))
+    }
+
+    fn lower(&self, program: &mut Program, tater: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Call(program.label(&self.0, tater)?)))
+    }
+
+    fn name(&self) -> &str {
+        "call"
+    }
+
+    fn source(&self) -> String {
+        format!("{} {}", self.name(), self.0)
+    }
+}
+
+impl Instruction for Jump {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(1))?;
+
+        Ok(Box::new(Jump(args[0].to_string())))
+    }
+
+    fn lower(&self, program: &mut Program, tater: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Jump(program.label(&self.0, tater)?)))
+    }
 
-        Box::new(Call(args[0].to_string()))
+    fn name(&self) -> &str {
+        "jump"
     }
 
-    fn exec(&self, garden: &mut Garden, tater: &Tater) {
-        garden.call(tater, self.0.as_ref());
+    fn source(&self) -> String {
+        format!("{} {}", self.name(), self.0)
+    }
+}
+
+impl Instruction for JumpIf {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(1))?;
+
+        Ok(Box::new(JumpIf(args[0].to_string())))
+    }
+
+    fn lower(&self, program: &mut Program, tater: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::JumpIf(program.label(&self.0, tater)?)))
+    }
+
+    fn name(&self) -> &str {
+        "jumpif"
+    }
+
+    fn source(&self) -> String {
+        format!("{} {}", self.name(), self.0)
+    }
+}
+
+impl Instruction for JumpUnless {
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Exact(1))?;
+
+        Ok(Box::new(JumpUnless(args[0].to_string())))
+    }
+
+    fn lower(&self, program: &mut Program, tater: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::JumpUnless(program.label(&self.0, tater)?)))
+    }
+
+    fn name(&self) -> &str {
+        "jumpunless"
+    }
+
+    fn source(&self) -> String {
+        format!("{} {}", self.name(), self.0)
     }
 }
 
 impl Instruction for Extern {
-    fn new(name: &str, args: &[&str], err: &Error) -> Box<Instruction> {
-        err.check_args("instruction", name, args.len(), ArgumentType::Range(1, 2));
+    fn new(name: &str, args: &[&str], err: &Error) -> Result<Box<Instruction>, TaterError> {
+        err.check_args("instruction", name, args.len(), ArgumentType::Range(1, 2))?;
 
         let value = match args.len() == 2 {
-            true  => Value::new(args[1], err, false),
+            true  => Value::new(args[1], err, false)?,
             false => Value::Bignum(gmp::mpz::Mpz::one())
         };
 
-        Box::new(Extern {
+        Ok(Box::new(Extern {
             name: args[0].to_string(),
             val: value,
-        })
+        }))
+    }
+
+    // External calls take the 'Value' itself, so it is kept unresolved.
+    fn lower(&self, program: &mut Program, tater: &Tater) -> Result<Code, TaterError> {
+        Ok(Code::from(Op::Extern {
+            call: program.external(&self.name, tater)?,
+            value: program.value(&self.val),
+        }))
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        vec![&self.val]
+    }
+
+    fn name(&self) -> &str {
+        "extern"
     }
 
-    fn exec(&self, garden: &mut Garden, tater: &Tater) {
-        let call = tater.ext_calls.get(&self.name);
-        match call {
-            Some(f) => f(&self.val, garden, tater),
-            None => panic!("Invalid external call of name '{}'!", self.name)
-        }
+    fn source(&self) -> String {
+        format!("{} {}; {}", self.name(), self.name, self.val)
     }
 }
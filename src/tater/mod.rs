@@ -1,7 +1,13 @@
 pub mod tater;
 pub mod garden;
+pub mod bits;
 pub mod value;
 pub mod util;
+pub mod dump;
+pub mod format;
 pub mod error;
 
 pub mod instruction;
+pub mod bytecode;
+pub mod image;
+pub mod expr;
@@ -1,30 +1,153 @@
 extern crate gmp;
 
 use super::value::Value;
+use super::expr;
 
-const COMMENT_CHAR: char = '?';
-const ARGUMENT_CHAR: char = ';';
+pub const COMMENT_CHAR: char = '?';
+pub const ARGUMENT_CHAR: char = ';';
 
 use super::garden::Garden;
-use super::instruction::{Instruction, create_instruction};
+use super::instruction::{Instruction, INSTRUCTIONS, create_instruction, build_instruction};
+use super::bytecode::{Program, Code, Op};
+use super::image::{Image, is_image};
 use super::error::*;
 use super::util::*;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::mem;
 use std::fs::File;
+use std::path::{Path, PathBuf};
 
 use std::io;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 
 use std::str::FromStr;
 
+use self::gmp::mpz::Mpz;
+
+// Splits a line into the name of its instruction and its arguments.
+fn split_instruction(l: &str) -> Option<(&str, Vec<&str>)> {
+    let name = l.split_whitespace().next()?;
+    let start = l.find(name).unwrap() + name.len();
+
+    let args = split_arguments(l[start ..].trim(), ARGUMENT_CHAR)
+        .into_iter()
+        .map(|val| val.trim())
+        .filter(|val| val.trim() != "")
+        .collect();
+
+    Some((name, args))
+}
+
+// Replaces every word of 'l' that is the first of a pair with the second,
+// where words are split the way defines are.
+fn substitute(l: &str, words: &[(String, String)]) -> String {
+    let mut sep_chars:Vec<char> = Vec::new();
+    let strings:Vec<&str> = l.split(|c:char| {
+        if c.is_whitespace() {
+            sep_chars.push(c);
+            return true;
+        }
+        match c {
+            ',' | ';' | '[' | ']' | ':' | '-' | '<' | '>' => {
+                sep_chars.push(c);
+                true
+            },
+            _ => false
+        }
+    }).collect::<Vec<&str>>();
+
+    sep_chars.reverse();
+
+    strings.iter().map(|w| {
+        let mut ret = String::new();
+        let mut did = false;
+
+        for def in words {
+            if def.0 == *w {
+                ret.push_str(&format!("{}", def.1));
+                did = true;
+                break;
+            }
+        }
+
+        if !did {
+            ret.push_str(&format!("{}", w));
+        }
+
+        match sep_chars.pop() {
+            Some(c) => ret.push(c),
+            None => {}
+        };
+
+        ret
+    }).collect::<String>()
+}
+
+// How deeply macros may expand into other macros, to catch ones that
+// expand into themselves.
+const MACRO_DEPTH: usize = 64;
+
+// A '#macro name params...' block, with the lines up to its '#end' already
+// stripped of comments and with their strings expanded. Labels defined in
+// the body are renamed on every expansion, so that each gets its own.
+#[derive(Clone)]
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    body: Vec<String>,
+    labels: Vec<String>,
+    span: Span,
+}
+
+// An '#if', '#ifdef' or '#ifndef' that hasn't reached its '#endif'. Lines
+// are only parsed while every condition is active; 'taken' is set once one
+// of its branches was, so that its '#else' is skipped.
+struct Condition {
+    active: bool,
+    taken: bool,
+    had_else: bool,
+    span: Span,
+}
+
 pub struct Tater {
     code: Vec<Box<Instruction>>,
+    spans: Vec<Span>,
     defines: Vec<(String, String)>,
 
+    macros: Vec<Macro>,
+    recording: Option<Macro>,
+    conditions: Vec<Condition>,
+    expansions: usize,
+    depth: usize,
+
+    included: Vec<PathBuf>,
+    requiring: Vec<(PathBuf, String)>,
+
+    // The last global label, which local ones like '@.loop' belong to.
+    scope: String,
+    label_spans: HashMap<String, Span>,
+    link_errors: Vec<TaterError>,
+    program: Option<Program>,
+
     pub labels: HashMap<String, usize>,
-    pub ext_calls: HashMap<String, Box<Fn(&Value, &mut Garden, &Tater)>>,
+    pub ext_calls: HashMap<String, Box<Fn(&Value, &mut Garden, &Tater) -> Result<(), TaterError>>>,
     pub print_parsed: bool,
+    pub trace: bool,
+    pub search_path: Vec<PathBuf>,
+}
+
+// How much had been parsed at some point, so that 'Tater::restore' can
+// drop whatever a failed line added after it.
+pub struct Checkpoint {
+    code: usize,
+    defines: Vec<(String, String)>,
+    macros: usize,
+    conditions: usize,
+    included: usize,
+    link_errors: usize,
+    labels: HashSet<String>,
 }
 
 impl Tater {
@@ -83,20 +206,20 @@ impl Tater {
     }
 
     pub fn add_external_call<F>(&mut self, name: &str, external: F)
-            where F: 'static + Fn(&Value, &mut Garden, &Tater) {
+            where F: 'static + Fn(&Value, &mut Garden, &Tater) -> Result<(), TaterError> {
         self.ext_calls.insert(name.to_string(), Box::new(external));
     }
 
     fn add_default_external_calls(tater: &mut Tater) {
         tater.add_external_call("numprint", |v, e, _| {
-            print!("{}", boolvec_to_bignum(v.get_boolvec(e).as_slice()));
-            io::stdout().flush().ok().expect("Wasn't able to flush std:out!");
+            print!("{}", boolvec_to_bignum(v.get_boolvec(e)?.as_slice()));
+            Tater::flush()
         });
 
         tater.add_external_call("print", |v, e, _| {
             let mut chars: Vec<u8> = vec![];
 
-            let boolvec = v.get_boolvec(e);
+            let boolvec = v.get_boolvec(e)?;
             let value = boolvec.as_slice();
 
             for i in 0 .. (value.len() / 8) {
@@ -113,89 +236,154 @@ impl Tater {
             let s = String::from_utf8_lossy(chars.as_slice()).to_string();
 
             print!("{}", s);
-            io::stdout().flush().ok().expect("Wasn't able to flush std:out!");
+            Tater::flush()
+        });
+
+        // Input externs write into the pointer they are given and set
+        // 'validity' to false, zeroing the pointer, at EOF. 'readnum' fails
+        // on a line that isn't a number.
+        tater.add_external_call("readline", |v, e, _| {
+            let line = e.read_line()?;
+            Tater::store_input(v, e, line.map(|l| bytes_to_boolvec(&l)))
+        });
+
+        tater.add_external_call("readchar", |v, e, _| {
+            let b = e.read_byte()?;
+            Tater::store_input(v, e, b.map(|b| bytes_to_boolvec(&[b])))
+        });
+
+        tater.add_external_call("readnum", |v, e, _| {
+            let num = match e.read_line()? {
+                Some(line) => {
+                    let text = String::from_utf8_lossy(&line);
+                    let text = text.trim();
+                    let digits = text.trim_start_matches('-');
+
+                    // checked first, as gmp panics on some malformed numbers
+                    match digits != "" && digits.len() + 1 >= text.len() && digits.chars().all(|c| c.is_digit(10)) {
+                        true  => gmp::mpz::Mpz::from_str(text).ok(),
+                        false => return Err(TaterError::from(ErrorType::InvalidValue(
+                            format!("'{}' was read but isn't a decimal number", text)))),
+                    }
+                },
+                None => None,
+            };
+            Tater::store_input(v, e, num.map(|n| bignum_to_boolvec(&n)))
         });
     }
 
+    fn store_input(v: &Value, garden: &mut Garden, input: Option<Vec<bool>>) -> Result<(), TaterError> {
+        let pos  = v.get_ptr_position(garden)?;
+        let size = v.get_ptr_size(garden)?;
+
+        garden.validity = input.is_some();
+        garden.set_bits_boolvec(&input.unwrap_or(vec![]), pos, size)?;
+        Ok(())
+    }
+
+    fn flush() -> Result<(), TaterError> {
+        io::stdout().flush().map_err(|e| TaterError::from(ErrorType::Io(e.to_string())))
+    }
+
 
 
     fn parse_defines(&self, l: &String) -> String {
-        let mut sep_chars:Vec<char> = Vec::new();
-		let strings:Vec<&str> = l.split(|c:char| {
-			if c.is_whitespace() {
-				sep_chars.push(c);
-				return true;
-			}
-			match c {
-				',' | '[' | ']' | ':' | '-' | '<' | '>' => {
-					sep_chars.push(c);
-					true
-				},
-				_ => false
-			}
-		}).collect::<Vec<&str>>();
-
-		sep_chars.reverse();
-
-		strings.iter().map(|w| {
-			let mut ret = String::new();
-			let mut did = false;
-
-			for def in &self.defines {
-				if def.0 == *w {
-					ret.push_str(&format!("{}", def.1));
-					did = true;
-					break;
-				}
-			}
-
-			if !did {
-				ret.push_str(&format!("{}", w));
-			}
-
-			match sep_chars.pop() {
-				Some(c) => ret.push(c),
-				None => {}
-			};
-
-			ret
-        }).collect::<String>()
+        substitute(l, &self.defines)
     }
 
     pub fn new(print_parsed: bool) -> Tater {
         let mut tater = Tater {
             code: Vec::new(),
+            spans: Vec::new(),
             defines: Vec::new(),
+            macros: Vec::new(),
+            recording: None,
+            conditions: Vec::new(),
+            expansions: 0,
+            depth: 0,
+            included: Vec::new(),
+            requiring: Vec::new(),
+            scope: String::new(),
+            label_spans: HashMap::new(),
+            link_errors: Vec::new(),
+            program: None,
             labels: HashMap::new(),
             ext_calls: HashMap::new(),
-            print_parsed: print_parsed
+            print_parsed: print_parsed,
+            trace: false,
+            search_path: Vec::new(),
         };
 
         Tater::add_default_external_calls(&mut tater);
         tater
     }
 
-    fn parse_args(&mut self, iname: &String, arguments: &[&str], err: &Error){
-		let ins = create_instruction(iname.as_ref(), arguments, self, err);
+    fn parse_args(&mut self, iname: &String, arguments: &[&str], err: &Error) -> Result<(), TaterError> {
+		let ins = create_instruction(iname.as_ref(), arguments, self, err)?;
 		self.code.push(ins);
+		self.spans.push(err.span().clone());
+		self.program = None;
+        Ok(())
     }
 
-    fn parse_labels(&mut self, line: &String) -> bool {
+    fn parse_labels(&mut self, line: &String, err: &Error) -> bool {
         if line.chars().next() == Some('@') {
-            let name = line[1 ..].to_string();
+            let name = self.qualify(&line[1 ..]);
+            if !line[1 ..].starts_with('.') {
+                self.scope = name.clone();
+            }
+
+            match self.label_spans.get(&name) {
+                Some(first) => {
+                    self.link_errors.push(err.error(ErrorType::Duplicate {
+                        type_name: "label".to_string(),
+                        value: name,
+                        first: first.clone(),
+                    }));
+                    return true
+                },
+                None => {},
+            }
+
+            self.label_spans.insert(name.clone(), err.span().clone());
             self.labels.insert(name, self.code.len());
+            self.program = None;
             return true
         }
         false
     }
 
-    fn parse_macros(&mut self, l: &String, err: &Error) -> bool {
+    // The full name of a label: local ones, starting with '.', are put
+    // after the global label they belong to.
+    fn qualify(&self, name: &str) -> String {
+        match name.starts_with('.') {
+            true  => format!("{}{}", self.scope, name),
+            false => name.to_string(),
+        }
+    }
+
+    // Qualifies every word of an instruction that names a local label.
+    fn qualify_line(&self, l: &str) -> String {
+        let mut ret = String::new();
+        let mut starts_word = true;
+
+        for c in l.chars() {
+            if starts_word && c == '.' {
+                ret.push_str(&self.scope);
+            }
+            ret.push(c);
+            starts_word = c.is_whitespace() || c == ARGUMENT_CHAR;
+        }
+        ret
+    }
+
+    fn parse_macros(&mut self, l: &String, err: &Error) -> Result<bool, TaterError> {
         if l.chars().next() == Some('#') {
             let macro_text = l[1 ..].trim();
             let mut macro_args: Vec<&str> = macro_text.split_whitespace().collect();
 
             if macro_args.len() < 1 {
-                err.throw(ErrorType::Empty("macro".to_string()))
+                return Err(err.error(ErrorType::Empty("macro".to_string())))
             }
 
             let macro_name = macro_args[0];
@@ -205,85 +393,914 @@ impl Tater {
 
             match macro_name {
                 "define" => {
-                    err.check_args("macro", macro_name, macro_args.len(), ArgumentType::AtLeast(2));
+                    err.check_args("macro", macro_name, macro_args.len(), ArgumentType::AtLeast(2))?;
 
                     let name = macro_args[0].to_string();
                     let args = macro_args[1 ..].join(" ");
 
                     self.defines.push((name, args));
                 },
-                "require" => {
-                    err.check_args("macro", macro_name, macro_args.len(), ArgumentType::AtLeast(1));
-
-                    let file = File::open(macro_total_args).unwrap();
-                    let buffer = BufReader::new(&file);
-                    let mut line = 0;
-                    for l2 in buffer.lines() {
-                        line += 1;
-                        let l1: String = l2.unwrap();
-                        self.parse_line(&l1, line, Some(macro_total_args.to_string()));
+                "macro" => {
+                    err.check_args("macro", macro_name, macro_args.len(), ArgumentType::AtLeast(1))?;
+
+                    // macros are looked up first, so they mustn't hide anything
+                    let hides = match macro_args[0] {
+                        name if INSTRUCTIONS.contains(&name) => Some("instruction"),
+                        name if self.ext_calls.contains_key(name) => Some("external call"),
+                        _ => None,
+                    };
+                    if let Some(type_name) = hides {
+                        return Err(err.error(ErrorType::Generic(format!(
+                            "macro '{}' has the name of an {}", macro_args[0], type_name))))
                     }
+
+                    self.recording = Some(Macro {
+                        name: macro_args[0].to_string(),
+                        params: macro_args[1 ..].iter().map(|p| p.to_string()).collect(),
+                        body: vec![],
+                        labels: vec![],
+                        span: err.span().clone(),
+                    });
                 },
-                name => err.throw(ErrorType::NonExistent {
+                "undef" => {
+                    err.check_args("macro", macro_name, macro_args.len(), ArgumentType::Exact(1))?;
+
+                    self.defines.retain(|def| def.0 != macro_args[0]);
+                },
+                "end" => return Err(err.error(ErrorType::Generic(
+                    "'#end' without a '#macro' before it".to_string()))),
+                "require" => {
+                    err.check_args("macro", macro_name, macro_args.len(), ArgumentType::AtLeast(1))?;
+
+                    let path = self.find(macro_total_args, &err.span().file).map_err(|e| err.error(e))?;
+                    self.parse_file(&path).map_err(|e| e.at(err.span()))?;
+                },
+                name => return Err(err.error(ErrorType::NonExistent {
                     type_name: "macro".to_string(),
                     value: name.to_string()
-                })
+                }))
             }
-            return true
+            return Ok(true)
         }
-        false
+        Ok(false)
     }
 
-    pub fn parse_line(&mut self, line_arg: &String, line: usize, path: Option<String>) {
+    pub fn parse_line(&mut self, line_arg: &String, line: usize, path: Option<String>) -> Result<(), TaterError> {
         let mut l: String = line_arg.to_string();
         remove_comments(&mut l, COMMENT_CHAR);
 
+        let column = l.chars().take_while(|c| c.is_whitespace()).count() + 1;
         l = l.trim().to_string();
 
-        let err = Error::new(l.clone(), line, path);
+        let err = Error::new(l.clone(), line, column, path);
 
         l = self.parse_strings(&l);
 
-        if self.parse_macros(&l, &err) {
-            return;
+        if self.record(&l, &err)? {
+            return Ok(());
+        }
+
+        if self.parse_conditionals(&l, &err)? {
+            return Ok(());
+        }
+
+        if self.parse_macros(&l, &err)? {
+            return Ok(());
         }
 
-        if self.parse_labels(&l) {
-            return;
+        if self.parse_labels(&l, &err) {
+            return Ok(());
         }
 
         l = self.parse_defines(&l);
+        l = self.qualify_line(&l);
+
+        match split_instruction(&l) {
+            Some((name, arg_vec)) => match self.macros.iter().rposition(|m| m.name == name) {
+                Some(index) => self.expand(index, &arg_vec, &err),
+                None => self.parse_args(&name.to_string(), &arg_vec, &err),
+            },
+            None => Ok(()),
+        }
+    }
+
+    // Handles '#if', '#ifdef', '#ifndef', '#else' and '#endif', and skips
+    // every other line while a condition doesn't hold.
+    fn parse_conditionals(&mut self, l: &String, err: &Error) -> Result<bool, TaterError> {
+        let live = self.conditions.iter().all(|c| c.active);
+
+        let (directive, rest) = match l.starts_with('#') {
+            true  => {
+                let text = l[1 ..].trim();
+                let name = text.split_whitespace().next().unwrap_or("");
+                (name, text[name.len() ..].trim())
+            },
+            false => ("", ""),
+        };
+
+        match directive {
+            "if" | "ifdef" | "ifndef" => {
+                let holds = live && match directive {
+                    "if" => expr::evaluate(rest, &self.defines).map_err(|e| err.error(e))? != Mpz::zero(),
+                    _ => {
+                        err.check_args("macro", directive, rest.split_whitespace().count(), ArgumentType::Exact(1))?;
+                        self.defines.iter().any(|def| def.0 == rest) == (directive == "ifdef")
+                    },
+                };
+
+                self.conditions.push(Condition {
+                    active: holds,
+                    taken: holds || !live,
+                    had_else: false,
+                    span: err.span().clone(),
+                });
+            },
+            "else" => {
+                let cond = match self.conditions.last_mut() {
+                    Some(cond) => cond,
+                    None => return Err(err.error(ErrorType::Generic(
+                        "'#else' without an '#if' before it".to_string()))),
+                };
+
+                if cond.had_else {
+                    return Err(err.error(ErrorType::Generic(
+                        format!("the '#if' at {} already has an '#else'", cond.span))))
+                }
+
+                cond.had_else = true;
+                cond.active = !cond.taken;
+                cond.taken = true;
+            },
+            "endif" => if self.conditions.pop().is_none() {
+                return Err(err.error(ErrorType::Generic(
+                    "'#endif' without an '#if' before it".to_string())))
+            },
+            _ => return Ok(!live),
+        }
+        Ok(true)
+    }
+
+    // Fails if an '#if' after the first 'depth' has no '#endif' yet, which
+    // it needs within the same file or macro.
+    fn close_conditions(&mut self, depth: usize) -> Result<(), TaterError> {
+        if self.conditions.len() <= depth {
+            return Ok(())
+        }
+
+        let span = self.conditions[depth].span.clone();
+        self.conditions.truncate(depth);
 
-        let mut arg_string: String = String::new();
+        Err(TaterError::from(ErrorType::Generic(
+            format!("'{}' has no '#endif'", span.text))).at(&span))
+    }
+
+    // Adds a line to the macro being recorded, if there is one, finishing
+    // it at '#end'.
+    fn record(&mut self, l: &String, err: &Error) -> Result<bool, TaterError> {
+        if self.recording.is_none() {
+            return Ok(false)
+        }
 
-        let name: String = match l.split_whitespace().next() {
-            Some(val) => {val.to_string()},
-            None => return,
+        let directive = match l.starts_with('#') {
+            true  => l[1 ..].split_whitespace().next().unwrap_or(""),
+            false => "",
         };
 
-        match l.find(name.as_str()) {
-            Some(p) => {
-                arg_string = l[(p + name.len()) ..].trim().to_string();
+        match directive {
+            "end" => {
+                let mac = self.recording.take().unwrap();
+
+                if let Some(first) = self.macros.iter().find(|m| m.name == mac.name) {
+                    return Err(err.error(ErrorType::Duplicate {
+                        type_name: "macro".to_string(),
+                        value: mac.name.clone(),
+                        first: first.span.clone(),
+                    }))
+                }
+                self.macros.push(mac);
+            },
+            "macro" => {
+                let name = self.recording.take().unwrap().name;
+                return Err(err.error(ErrorType::Generic(
+                    format!("'#macro' inside of macro '{}', which needs an '#end' first", name))))
+            },
+            _ if l == "" => {},
+            _ => {
+                let mac = self.recording.as_mut().unwrap();
+                if l.starts_with('@') {
+                    mac.labels.push(l[1 ..].to_string());
+                }
+                mac.body.push(l.clone());
+            },
+        }
+        Ok(true)
+    }
+
+    // Parses the body of a macro in place of the line calling it, with its
+    // parameters replaced by the arguments it was given.
+    fn expand(&mut self, index: usize, args: &[&str], err: &Error) -> Result<(), TaterError> {
+        let mac = self.macros[index].clone();
+        err.check_args("macro", &mac.name, args.len(), ArgumentType::Exact(mac.params.len()))?;
+
+        if self.depth >= MACRO_DEPTH {
+            return Err(err.error(ErrorType::Generic(format!(
+                "macro '{}' expands more than {} levels deep, does it use itself?", mac.name, MACRO_DEPTH))))
+        }
+
+        self.expansions += 1;
+
+        let mut words: Vec<(String, String)> = mac.params.iter().zip(args)
+            .map(|(param, arg)| (param.clone(), arg.to_string())).collect();
+
+        // renamed to local labels, so that using a macro doesn't change the
+        // scope of the ones after it
+        for label in &mac.labels {
+            words.push((label.clone(), format!(".{}~{}~{}",
+                mac.name, self.expansions, label.trim_start_matches('.'))));
+        }
+
+        let span = err.span();
+        let depth = self.conditions.len();
+        self.depth += 1;
+
+        let mut result = Ok(());
+        for line in &mac.body {
+            let line = match line.starts_with('@') {
+                true  => format!("@{}", substitute(&line[1 ..], &words)),
+                false => substitute(line, &words),
+            };
+
+            result = self.parse_line(&line, span.line, span.file.clone());
+            if result.is_err() {
+                break
             }
-            None => {},
         }
 
-        let arg_vec: Vec<&str> = arg_string
-            .split(ARGUMENT_CHAR)
-            .map(|val| val.trim())
-            .filter(|val| val.trim() != "")
-            .collect();
+        self.depth -= 1;
+        result.and_then(|_| self.close_conditions(depth))
+    }
+
+    // Loads 'path' as an image if it is one, or parses it as source.
+    pub fn load_file(&mut self, path: &str) -> Result<(), TaterError> {
+        let mut bytes = vec![];
+        File::open(path).and_then(|mut file| file.read_to_end(&mut bytes)).map_err(
+            |e| TaterError::from(ErrorType::Io(format!("'{}': {}", path, e))))?;
+
+        match is_image(&bytes) {
+            true  => self.load_image(&bytes).map_err(|e| match e.kind {
+                ErrorType::Image(message) => TaterError::from(
+                    ErrorType::Image(format!("'{}': {}", path, message))),
+                _ => e,
+            }),
+            false => self.parse_file(path),
+        }
+    }
+
+    // The linked program along with its labels and source map, as written
+    // to '.tatc' files.
+    pub fn image(&mut self) -> Result<Vec<u8>, TaterError> {
+        self.link()?;
+
+        let mut labels: Vec<(String, usize, Span)> = self.labels.iter().map(
+            |(name, index)| (name.clone(), *index, self.label_spans[name].clone())).collect();
+        labels.sort_by_key(|&(ref name, index, _)| (index, name.clone()));
+
+        let image = Image {
+            spans: self.spans.clone(),
+            sources: self.code.iter().map(|ins| ins.source()).collect(),
+            labels: labels,
+            defines: self.defines.clone(),
+            program: self.program.take().unwrap(),
+        };
+
+        let bytes = image.write();
+        self.program = Some(image.program);
+        Ok(bytes)
+    }
+
+    // Loads what 'image' wrote, which has to come before anything else is
+    // parsed. Images only skip what it takes to get to the instructions:
+    // reading '#require'd files, substituting defines and expanding macros
+    // and conditions. Each instruction is still parsed again from its
+    // canonical source so that tracing, debugging, disasm and later lines
+    // in the repl work, but the bytecode is used as is rather than linked,
+    // once it is known to be what those instructions lower to.
+    pub fn load_image(&mut self, bytes: &[u8]) -> Result<(), TaterError> {
+        if !self.code.is_empty() || !self.labels.is_empty() {
+            return Err(TaterError::from(ErrorType::Image(
+                "an image can't be loaded after other code".to_string())))
+        }
+
+        let image = Image::read(bytes)?;
+
+        for name in &image.program.externs {
+            if !self.ext_calls.contains_key(name) {
+                return Err(TaterError::from(ErrorType::NonExistent {
+                    type_name: "external call".to_string(),
+                    value: name.to_string(),
+                }))
+            }
+        }
+
+        for (name, index, span) in image.labels {
+            self.label_spans.insert(name.clone(), span);
+            self.labels.insert(name, index);
+        }
+
+        let result = self.load_sources(image.spans, image.sources, &image.program);
+        if result.is_err() {
+            self.code.clear();
+            self.spans.clear();
+            self.labels.clear();
+            self.label_spans.clear();
+            return result
+        }
+
+        self.defines.extend(image.defines);
+        self.program = Some(image.program);
+        Ok(())
+    }
+
+    // Parses the sources of an image, failing unless lowering them gives
+    // back 'program' exactly.
+    fn load_sources(&mut self, spans: Vec<Span>, sources: Vec<String>, program: &Program) -> Result<(), TaterError> {
+        for (span, source) in spans.into_iter().zip(sources) {
+            let err = Error::new(span.text.clone(), span.line, span.column, span.file.clone());
+
+            let ins = match split_instruction(&source) {
+                Some((name, args)) => build_instruction(name, &args, &err)?,
+                None => return Err(err.error(ErrorType::Empty("instruction".to_string()))),
+            };
+
+            self.code.push(ins);
+            self.spans.push(span);
+        }
+
+        let mut lowered = Program::new();
+
+        for (i, ins) in self.code.iter().enumerate() {
+            let code = ins.lower(&mut lowered, self).map_err(|e| e.at(&self.spans[i]))?;
+
+            if code != program.code[i] {
+                return Err(TaterError::from(ErrorType::Image(format!(
+                    "instruction {} is '{}' but its bytecode is {:?}", i, ins.source(), program.code[i])))
+                    .at(&self.spans[i]))
+            }
+        }
+
+        if lowered.constants != program.constants || lowered.values != program.values
+            || lowered.externs != program.externs {
+            return Err(TaterError::from(ErrorType::Image(
+                "the operands of its bytecode aren't those of its instructions".to_string())))
+        }
+        Ok(())
+    }
+
+    // Finds the file '#require path' means: relative to the file requiring
+    // it, or the working directory in the repl, and then relative to each
+    // directory of 'search_path' in turn.
+    fn find(&self, path: &str, from: &Option<String>) -> Result<String, ErrorType> {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            return Ok(path.to_string_lossy().into_owned())
+        }
+
+        let base = match *from {
+            Some(ref file) => Path::new(file).parent().map_or(PathBuf::new(), |dir| dir.to_path_buf()),
+            None => PathBuf::new(),
+        };
+
+        let mut tried = vec![];
+
+        for dir in Some(base).into_iter().chain(self.search_path.iter().cloned()) {
+            let candidate = dir.join(path);
+            if candidate.is_file() {
+                return Ok(candidate.to_string_lossy().into_owned())
+            }
+            tried.push(format!("'{}'", candidate.display()));
+        }
 
-        self.parse_args(&name, &arg_vec, &err);
+        Err(ErrorType::Io(format!("can't find '{}', looked for {}", path.display(), tried.join(", "))))
     }
 
-    pub fn run(&mut self, garden: &mut Garden) {
-        let len = self.code.len();
+    // Parses a file unless it has been already, by its canonical path, so
+    // that requiring a file more than once only adds its code once. A file
+    // requiring itself, even through others, is an error.
+    pub fn parse_file(&mut self, path: &str) -> Result<(), TaterError> {
+        let canonical = fs::canonicalize(path).unwrap_or(PathBuf::from(path));
+
+        if self.requiring.iter().any(|r| r.0 == canonical) {
+            let mut chain: Vec<&str> = self.requiring.iter().map(|r| r.1.as_str()).collect();
+            chain.push(path);
+
+            return Err(TaterError::from(ErrorType::Generic(
+                format!("'{}' requires itself: {}", path, chain.join(" -> ")))))
+        }
+
+        if self.included.contains(&canonical) {
+            return Ok(())
+        }
+
+        self.included.push(canonical.clone());
+        self.requiring.push((canonical, path.to_string()));
+
+        // every file starts outside of any global label
+        let scope = mem::replace(&mut self.scope, String::new());
+        let result = self.parse_lines(path);
+
+        self.scope = scope;
+        self.requiring.pop();
+        result
+    }
+
+    fn parse_lines(&mut self, path: &str) -> Result<(), TaterError> {
+        let io_error = |e: io::Error| TaterError::from(
+            ErrorType::Io(format!("'{}': {}", path, e)));
+
+        let file = File::open(path).map_err(&io_error)?;
+        let buffer = BufReader::new(&file);
+
+        let mut line: usize = 0usize;
+        let depth = self.conditions.len();
+
+        for ln in buffer.lines() {
+            let l: String = ln.map_err(&io_error)?;
+
+            line += 1usize;
+
+            self.parse_line(&l, line, Some(path.to_string()))?;
+        }
+
+        if let Some(mac) = self.recording.take() {
+            return Err(TaterError::from(ErrorType::Generic(
+                format!("macro '{}' has no '#end'", mac.name))).at(&mac.span))
+        }
+        self.close_conditions(depth)
+    }
+
+    // Lowers the parsed program to bytecode, resolving every label and
+    // external call and reporting all that are missing or duplicated at once.
+    pub fn link(&mut self) -> Result<(), TaterError> {
+        let mut errors = self.link_errors.clone();
+        let mut program = Program::new();
+
+        for (i, ins) in self.code.iter().enumerate() {
+            let code = match ins.lower(&mut program, self) {
+                Ok(code) => code,
+                Err(e) => {
+                    errors.push(e.at(&self.spans[i]));
+                    Code::from(Op::Nop)
+                },
+            };
+            program.code.push(code);
+        }
+
+        TaterError::collect(errors)?;
+        self.program = Some(program);
+        Ok(())
+    }
+
+    // The bytecode of the program, once it has been linked.
+    pub fn program(&self) -> Option<&Program> {
+        self.program.as_ref()
+    }
+
+    pub fn run(&mut self, garden: &mut Garden) -> Result<(), TaterError> {
+        if self.program.is_none() {
+            self.link()?;
+        }
+
+        if self.trace {
+            return self.run_traced(garden)
+        }
+
+        let program = self.program.as_ref().unwrap();
+        let len = program.len();
+
         while garden.instruction < len {
-            let ins = &self.code[garden.instruction];
+            let index = garden.instruction;
             garden.instruction += 1;
 
-            ins.exec(garden, self);
+            program.exec(index, garden, self).map_err(|e| e.at(&self.spans[index]))?;
+        }
+        Ok(())
+    }
+
+    // The loop of 'run', logging to stderr what every instruction was given
+    // and how it changed the stack.
+    fn run_traced(&self, garden: &mut Garden) -> Result<(), TaterError> {
+        let program = self.program.as_ref().unwrap();
+
+        let mut before: Vec<u64> = vec![];
+        let mut after: Vec<u64> = vec![];
+
+        while garden.instruction < program.len() {
+            let index = garden.instruction;
+            let span = &self.spans[index];
+
+            let operands: Vec<String> = self.code[index].operands().iter().map(
+                |v| match v.get_bignum(garden) {
+                    Ok(num) => format!("{} = {}", v, num),
+                    Err(_) => format!("{} = ?", v),
+                }).collect();
+
+            let skipped = match program.code[index].when {
+                Some(when) => when != garden.validity,
+                None => false,
+            };
+
+            let len = garden.stack_len();
+            garden.read_words(0, len, &mut before)?;
+
+            garden.instruction += 1;
+            let result = program.exec(index, garden, self).map_err(|e| e.at(span));
+
+            let new_len = garden.stack_len();
+            garden.read_words(0, new_len, &mut after)?;
+
+            let changed: Vec<String> = changed_ranges(&before, &after).iter().map(
+                |&(start, end)| format!("{}->{}", start, end)).collect();
+
+            eprintln!("[trace] {}: {} >>> {}{}", index, span, span.text, match skipped {
+                true  => " (skipped)",
+                false => "",
+            });
+            if !operands.is_empty() {
+                eprintln!("        operands: {}", operands.join(", "));
+            }
+            eprintln!("        stack: {} -> {} bit(s){}", len, new_len, match changed.is_empty() {
+                true  => "".to_string(),
+                false => format!(", changed {}", changed.join(", ")),
+            });
+
+            result?;
+        }
+        Ok(())
+    }
+
+    // Runs the instruction 'garden' is at, linking first if needed; returns
+    // false once there is nothing left to run.
+    pub fn step(&mut self, garden: &mut Garden) -> Result<bool, TaterError> {
+        if self.program.is_none() {
+            self.link()?;
+        }
+
+        let program = self.program.as_ref().unwrap();
+        let index = garden.instruction;
+
+        if index >= program.len() {
+            return Ok(false)
+        }
+
+        garden.instruction += 1;
+        program.exec(index, garden, self).map_err(|e| e.at(&self.spans[index]))?;
+        Ok(true)
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            code: self.code.len(),
+            // copied whole, as '#undef' can remove any of them
+            defines: self.defines.clone(),
+            macros: self.macros.len(),
+            conditions: self.conditions.len(),
+            included: self.included.len(),
+            link_errors: self.link_errors.len(),
+            labels: self.labels.keys().cloned().collect(),
+        }
+    }
+
+    pub fn restore(&mut self, point: Checkpoint) {
+        self.code.truncate(point.code);
+        self.spans.truncate(point.code);
+        self.defines = point.defines;
+        self.macros.truncate(point.macros);
+        self.conditions.truncate(point.conditions);
+        self.included.truncate(point.included);
+        self.link_errors.truncate(point.link_errors);
+
+        let labels = point.labels;
+        self.labels.retain(|name, _| labels.contains(name));
+        self.label_spans.retain(|name, _| labels.contains(name));
+
+        self.program = None;
+    }
+
+    // Defines 'name' as if by '#define', for '-D' on the command line.
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.defines.push((name.to_string(), value.to_string()));
+    }
+
+    // Every '#define' so far, as (name, replacement).
+    pub fn defines(&self) -> &[(String, String)] {
+        &self.defines
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn span(&self, index: usize) -> Option<&Span> {
+        self.spans.get(index)
+    }
+
+    pub fn instruction(&self, index: usize) -> Option<&(Instruction + '_)> {
+        self.code.get(index).map(|ins| &**ins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tater;
+    use super::super::garden::Garden;
+    use super::super::image::Image;
+    use super::super::instruction::tests::{run, bits, fails_with};
+    use super::super::error::*;
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    // Parses 'source' a line at a time, as the repl does.
+    fn parse(source: &str) -> Result<Tater, TaterError> {
+        let mut tater = Tater::new(false);
+        for (i, line) in source.lines().enumerate() {
+            tater.parse_line(&line.to_string(), i + 1, None)?;
+        }
+        Ok(tater)
+    }
+
+    // What running 'source' fails with, without where.
+    fn error(source: &str) -> String {
+        match run(source) {
+            Ok(_) => panic!("'{}' ran", source),
+            Err(e) => e.kind.to_string(),
         }
     }
+
+    // A new directory holding 'files', removed again when dropped.
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Dir {
+            let dir = env::temp_dir().join(format!("tater-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+
+            for &(path, text) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, text).unwrap();
+            }
+            Dir(dir)
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.0.join(file).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn macro_parameters() {
+        let garden = run("#macro set to value\nmove to; value\n#end\nput 8\nput 8\nset [0; 8]; 9\nset [8; 8]; [0; 8]")
+            .unwrap();
+        assert_eq!(bits(&garden, 0, 8), "9");
+        assert_eq!(bits(&garden, 8, 8), "9");
+
+        // only whole words are parameters
+        let garden = run("#macro set a\nput 8; a\nput 8; aa\n#end\n#define aa 3\nset 5").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "5");
+        assert_eq!(bits(&garden, 8, 8), "3");
+    }
+
+    #[test]
+    fn macro_labels() {
+        let source = "#macro count to\nadd to; 1\nless to; 3\njumpif again\njump done\n@again\nadd to; 10\n@done\n#end\n\
+                      put 8\nput 8; 5\ncount [0; 8]\ncount [8; 8]";
+        let garden = run(source).unwrap();
+        assert_eq!(bits(&garden, 0, 8), "11");
+        assert_eq!(bits(&garden, 8, 8), "6");
+
+        let tater = parse(source).unwrap();
+        let mut labels: Vec<&String> = tater.labels.keys().filter(|l| l.contains("again")).collect();
+        labels.sort();
+        assert_eq!(labels.len(), 2);
+        assert!(labels[0] != labels[1]);
+    }
+
+    #[test]
+    fn macro_arity() {
+        fails_with("#macro set to value\nmove to; value\n#end\nput 8\nset [0; 8]", "ArgumentError");
+        fails_with("#macro clear to\nmove to; 0\n#end\nput 8\nclear [0; 8]; 1", "ArgumentError");
+        fails_with("#macro nothing\n#end\nnothing 1", "ArgumentError");
+    }
+
+    #[test]
+    fn macro_recursion() {
+        assert!(error("#macro forever\nforever\n#end\nforever").contains("levels deep"));
+        assert!(error("#macro ping\npong\n#end\n#macro pong\nping\n#end\nping").contains("levels deep"));
+
+        // nesting that ends is fine
+        let garden = run("#macro one\nput 1; 1\n#end\n#macro two\none\none\n#end\ntwo").unwrap();
+        assert_eq!(bits(&garden, 0, 2), "3");
+    }
+
+    #[test]
+    fn macro_without_end() {
+        let dir = Dir::new("unended", &[("main.tat", "put 1\n#macro open\nput 2\n")]);
+
+        let mut tater = Tater::new(false);
+        let e = tater.parse_file(&dir.path("main.tat")).err().unwrap();
+        assert!(e.kind.to_string().contains("macro 'open' has no '#end'"), "{}", e);
+        assert_eq!(e.location.unwrap().line, 2);
+
+        assert!(error("#end").contains("without a '#macro'"));
+    }
+
+    #[test]
+    fn nested_macros() {
+        assert!(error("#macro outer\n#macro inner\n#end\n#end").contains("'#macro' inside of macro 'outer'"));
+    }
+
+    #[test]
+    fn macro_names() {
+        assert!(error("#macro put a\n#end").contains("has the name of an instruction"));
+        assert!(error("#macro jumpif\n#end").contains("has the name of an instruction"));
+        assert!(error("#macro print a\n#end").contains("has the name of an external call"));
+
+        fails_with("#macro twice\n#end\n#macro twice\n#end", "Duplicate");
+    }
+
+    // Parses and runs 'main.tat' from 'dir'.
+    fn run_file(tater: &mut Tater, dir: &Dir) -> Result<Garden, TaterError> {
+        tater.parse_file(&dir.path("main.tat"))?;
+
+        let mut garden = Garden::with_seed(0);
+        tater.run(&mut garden)?;
+        Ok(garden)
+    }
+
+    #[test]
+    fn require_relative_to_the_requiring_file() {
+        let dir = Dir::new("relative", &[
+            ("main.tat", "put 8\n#require lib/a.tat"),
+            ("lib/a.tat", "#require b.tat"),
+            ("lib/b.tat", "move [0; 8]; 7"),
+            ("b.tat", "move [0; 8]; 1"),
+        ]);
+
+        let garden = run_file(&mut Tater::new(false), &dir).unwrap();
+        assert_eq!(bits(&garden, 0, 8), "7");
+    }
+
+    #[test]
+    fn require_from_the_search_path() {
+        let dir = Dir::new("search", &[
+            ("main.tat", "put 8\n#require set.tat"),
+            ("first/set.tat", "move [0; 8]; 3"),
+            ("second/set.tat", "move [0; 8]; 4"),
+        ]);
+
+        let mut tater = Tater::new(false);
+        tater.search_path = vec![dir.0.join("first"), dir.0.join("second")];
+        let garden = run_file(&mut tater, &dir).unwrap();
+        assert_eq!(bits(&garden, 0, 8), "3");
+
+        // beside the requiring file comes before the search path
+        let dir = Dir::new("search-local", &[
+            ("main.tat", "put 8\n#require set.tat"),
+            ("set.tat", "move [0; 8]; 2"),
+            ("first/set.tat", "move [0; 8]; 3"),
+        ]);
+
+        let mut tater = Tater::new(false);
+        tater.search_path = vec![dir.0.join("first")];
+        let garden = run_file(&mut tater, &dir).unwrap();
+        assert_eq!(bits(&garden, 0, 8), "2");
+
+        let mut tater = Tater::new(false);
+        let e = tater.parse_line(&"#require missing.tat".to_string(), 1, Some(dir.path("main.tat")))
+            .err().unwrap();
+        assert!(e.kind.to_string().contains("can't find 'missing.tat'"), "{}", e);
+    }
+
+    #[test]
+    fn require_once() {
+        let dir = Dir::new("once", &[
+            ("main.tat", "put 8\n#require add.tat\n#require ./add.tat\n#require lib/again.tat"),
+            ("add.tat", "add [0; 8]; 1"),
+            ("lib/again.tat", "#require ../add.tat"),
+        ]);
+
+        let mut tater = Tater::new(false);
+        let garden = run_file(&mut tater, &dir).unwrap();
+        assert_eq!(bits(&garden, 0, 8), "1");
+        assert_eq!(tater.len(), 2);
+    }
+
+    #[test]
+    fn require_cycles() {
+        let dir = Dir::new("cycle", &[
+            ("main.tat", "#require a.tat"),
+            ("a.tat", "put 1\n#require b.tat"),
+            ("b.tat", "#require main.tat"),
+        ]);
+
+        let e = run_file(&mut Tater::new(false), &dir).err().unwrap();
+        let message = e.kind.to_string();
+        assert!(message.contains("requires itself"), "{}", message);
+        assert!(message.contains(&format!("{} -> {} -> {} -> {}",
+            dir.path("main.tat"), dir.path("a.tat"), dir.path("b.tat"), dir.path("main.tat"))), "{}", message);
+
+        let dir = Dir::new("self", &[("main.tat", "#require main.tat")]);
+        let e = run_file(&mut Tater::new(false), &dir).err().unwrap();
+        assert!(e.kind.to_string().contains("requires itself"), "{}", e);
+    }
+
+    #[test]
+    fn local_labels() {
+        let source = "put 8\ncall first\ncall second\njump end\n\
+                      @first\njump .skip\nmove [0; 8]; 1\n@.skip\nadd [0; 8]; 2\nreturn\n\
+                      @second\njump .skip\nmove [0; 8]; 1\n@.skip\nadd [0; 8]; 4\njump first.skip\n@end";
+        let tater = parse(source).unwrap();
+        assert!(tater.labels.contains_key("first.skip"));
+        assert!(tater.labels.contains_key("second.skip"));
+        assert!(!tater.labels.contains_key(".skip"));
+
+        // each '.skip' is its own global's, and 'first.skip' reaches across
+        let garden = run(source).unwrap();
+        assert_eq!(bits(&garden, 0, 8), "8");
+    }
+
+    #[test]
+    fn local_labels_before_globals() {
+        let tater = parse("@.start\nput 1\n@main\n@.start").unwrap();
+        assert!(tater.labels.contains_key(".start"));
+        assert!(tater.labels.contains_key("main.start"));
+
+        let garden = run("put 8\njump .over\nmove [0; 8]; 1\n@.over\nadd [0; 8]; 4").unwrap();
+        assert_eq!(bits(&garden, 0, 8), "4");
+    }
+
+    #[test]
+    fn local_label_diagnostics() {
+        let e = error("@first\n@.there\n@second\njump .there");
+        assert!(e.contains("second.there") && !e.contains("first.there"), "{}", e);
+
+        let e = error("@first\n@.twice\nput 1\n@.twice");
+        assert!(e.contains("first.twice"), "{}", e);
+        fails_with("@first\n@.twice\nput 1\n@.twice", "Duplicate");
+
+        // the same local name under different globals is fine
+        assert!(run("@first\n@.again\n@second\n@.again").is_ok());
+    }
+
+    const IMAGED: &'static str = "#define SIZE 8\n#macro greet text\nput 24; text\n#end\n\
+        @main\ngreet \"abc\"\nput SIZE\nequal [24; SIZE]; 0\nif jump .skip\nmove [24; 8]; 1\n\
+        @.skip\nunless add [24; 8]; 5\nif add [24; 8]; 2\ncall .twice\njump end\n\
+        @.twice\nadd [24; 8]; [24; 8]\nreturn\n@end";
+
+    fn image(source: &str) -> Vec<u8> {
+        parse(source).unwrap().image().unwrap()
+    }
+
+    #[test]
+    fn images_run_as_their_source() {
+        let expected = run(IMAGED).unwrap();
+
+        let mut tater = Tater::new(false);
+        tater.load_image(&image(IMAGED)).unwrap();
+
+        let mut garden = Garden::with_seed(0);
+        tater.run(&mut garden).unwrap();
+        assert_eq!(bits(&garden, 0, 32), bits(&expected, 0, 32));
+        assert_eq!(bits(&garden, 24, 8), "4");
+        assert_eq!(tater.len(), parse(IMAGED).unwrap().len());
+    }
+
+    #[test]
+    fn images_must_match_their_source() {
+        let tampered = |from: &str, to: &str| {
+            let mut image = Image::read(&image(IMAGED)).unwrap();
+            let index = image.sources.iter().position(|s| s == from).unwrap();
+            image.sources[index] = to.to_string();
+
+            let mut tater = Tater::new(false);
+            let e = tater.load_image(&image.write()).err().unwrap();
+            assert!(format!("{:?}", e.kind).starts_with("Image"), "{}", e);
+            assert_eq!(tater.len(), 0);
+        };
+
+        tampered("if add [24; 8]; 2", "if add [24; 8]; 3");
+        tampered("if add [24; 8]; 2", "unless add [24; 8]; 2");
+        tampered("if add [24; 8]; 2", "if sub [24; 8]; 2");
+        tampered("if add [24; 8]; 2", "if add [16; 8]; 2");
+        tampered("jump end", "jump main");
+    }
 }
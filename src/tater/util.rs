@@ -1,6 +1,63 @@
 #![allow(dead_code)]
 extern crate gmp;
 
+use self::gmp::mpz::{Mpz, mpz_ptr, mpz_srcptr, mp_limb_t, mp_bitcnt_t};
+
+use super::error::ErrorType;
+
+use std::mem::size_of;
+use std::os::raw::{c_int, c_void};
+
+// Limb level access to 'Mpz', which rust-gmp doesn't expose itself. The
+// types are rust-gmp's own, so these agree with how it declares the rest.
+#[link(name = "gmp")]
+extern "C" {
+    fn __gmpz_import(rop: mpz_ptr, count: usize, order: c_int, size: usize,
+                     endian: c_int, nails: usize, op: *const c_void);
+    fn __gmpz_size(op: mpz_srcptr) -> usize;
+    fn __gmpz_getlimbn(op: mpz_srcptr, n: usize) -> mp_limb_t;
+    fn __gmpz_fdiv_q_2exp(q: mpz_ptr, n: mpz_srcptr, b: mp_bitcnt_t);
+    fn __gmpz_fdiv_r_2exp(r: mpz_ptr, n: mpz_srcptr, b: mp_bitcnt_t);
+}
+
+pub const LIMB_BITS: usize = size_of::<mp_limb_t>() * 8;
+
+// Sets 'out' to the number whose 64 bit words, least significant first,
+// are 'words'; reuses the memory 'out' already holds.
+pub fn import_words(out: &mut Mpz, words: &[u64]) {
+    unsafe {
+        __gmpz_import(out.inner_mut(), words.len(), -1, size_of::<u64>(), 0, 0,
+                      words.as_ptr() as *const c_void);
+    }
+}
+
+// Number of limbs in the magnitude of 'num'.
+pub fn limb_count(num: &Mpz) -> usize {
+    unsafe { __gmpz_size(num.inner()) }
+}
+
+// Limb 'n' of the magnitude of 'num', zero past its end.
+pub fn limb(num: &Mpz, n: usize) -> usize {
+    unsafe { __gmpz_getlimbn(num.inner(), n) }
+}
+
+// num = floor(num / 2^bits), in place.
+pub fn shift_right(num: &mut Mpz, bits: usize) {
+    unsafe {
+        let ptr = num.inner_mut();
+        __gmpz_fdiv_q_2exp(ptr, ptr, bits as mp_bitcnt_t);
+    }
+}
+
+// num = num mod 2^bits, in place; negative numbers wrap around like two's
+// complement of that width.
+pub fn truncate_bits(num: &mut Mpz, bits: usize) {
+    unsafe {
+        let ptr = num.inner_mut();
+        __gmpz_fdiv_r_2exp(ptr, ptr, bits as mp_bitcnt_t);
+    }
+}
+
 pub fn remove_comments(val: &mut String, comment: char) {
     match val.find(comment) {
         Some(pos) => {
@@ -10,15 +67,66 @@ pub fn remove_comments(val: &mut String, comment: char) {
     }
 }
 
-pub fn boolvec_to_bignum(vec: &[bool]) -> gmp::mpz::Mpz {
-    let mut ret = gmp::mpz::Mpz::zero();
-    ret.reserve(vec.len());
+pub fn split_arguments(val: &str, separator: char) -> Vec<&str> {
+    let mut ret: Vec<&str> = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in val.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                ret.push(&val[start .. i]);
+                start = i + c.len_utf8();
+            },
+            _ => {}
+        }
+    }
+
+    ret.push(&val[start ..]);
+    ret
+}
+
+pub fn boolvec_to_words(vec: &[bool]) -> Vec<u64> {
+    let mut words = vec![0u64; (vec.len() + 63) / 64];
     for i in 0 .. vec.len() {
-        match vec[i] {
-            true => ret.setbit(i),
-            false => ret.clrbit(i)
+        if vec[i] {
+            words[i / 64] |= 1 << (i % 64);
+        }
+    }
+    words
+}
+
+// Ranges of bits, as (start, end), that differ between two runs of 64 bit
+// words; missing words count as zero.
+pub fn changed_ranges(before: &[u64], after: &[u64]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+
+    for i in 0 .. before.len().max(after.len()) {
+        let diff = before.get(i).cloned().unwrap_or(0) ^ after.get(i).cloned().unwrap_or(0);
+
+        for bit in 0 .. 64 {
+            if diff & (1 << bit) == 0 {
+                continue
+            }
+
+            let pos = i * 64 + bit;
+            if let Some(last) = ranges.last_mut() {
+                if last.1 == pos {
+                    last.1 += 1;
+                    continue
+                }
+            }
+            ranges.push((pos, pos + 1));
         }
     }
+    ranges
+}
+
+pub fn boolvec_to_bignum(vec: &[bool]) -> gmp::mpz::Mpz {
+    let mut ret = gmp::mpz::Mpz::zero();
+    import_words(&mut ret, &boolvec_to_words(vec));
     ret
 }
 
@@ -49,28 +157,47 @@ pub fn char_to_boolvec(c: char) -> Vec<bool> {
     str_to_boolvec(temp_string.as_ref())
 }
 
-pub fn bignum_to_usize(num: &gmp::mpz::Mpz) -> usize {
-    let mut ret: usize = 0;
-    for i in 0 .. num.bit_length() {
-        if num.tstbit(i) {
-            ret |= 1 << i;
-        }
+// 'num' as a size or position, which fails rather than wrapping around if
+// it is negative or doesn't fit.
+pub fn bignum_to_usize(num: &gmp::mpz::Mpz) -> Result<usize, ErrorType> {
+    if *num < Mpz::zero() {
+        return Err(ErrorType::InvalidValue(format!("{} is negative, so it can't be a size or position", num)))
+    }
+
+    match limb_count(num) <= 1 {
+        true  => Ok(limb(num, 0)),
+        false => Err(ErrorType::InvalidValue(format!("{} is too large for a size or position", num))),
     }
-    ret
 }
 
 pub fn bignum_to_boolvec(num: &gmp::mpz::Mpz) -> Vec<bool> {
     let num_bits = num.bit_length();
     let mut ret = vec![false; num_bits];
-    for i in 0 .. num_bits {
-        ret[i] = num.tstbit(i);
+
+    // negative numbers keep their two's complement bits
+    if *num < gmp::mpz::Mpz::zero() {
+        for i in 0 .. num_bits {
+            ret[i] = num.tstbit(i);
+        }
+        return ret
+    }
+
+    for n in 0 .. limb_count(num) {
+        let l = limb(num, n);
+        for i in 0 .. LIMB_BITS.min(num_bits - n * LIMB_BITS) {
+            ret[n * LIMB_BITS + i] = l & (1 << i) != 0;
+        }
     }
     ret
 }
 
 pub fn str_to_boolvec(s: &str) -> Vec<bool> {
+    bytes_to_boolvec(s.as_bytes())
+}
+
+pub fn bytes_to_boolvec(bytes: &[u8]) -> Vec<bool> {
     let mut ret: Vec<bool> = vec![];
-    for b in s.as_bytes() {
+    for b in bytes {
         for i in 0 .. 8 {
             ret.push((*b as u32) & (128 >> i) != 0);
         }
@@ -99,17 +226,133 @@ pub fn usize_to_boolvec(num: usize) -> Vec<bool> {
 }
 
 pub fn usize_to_bignum(num: usize) -> gmp::mpz::Mpz {
-    let mut ret = gmp::mpz::Mpz::zero();
-    let mut i = 0;
-    let mut num = num;
+    gmp::mpz::Mpz::from(num as u64)
+}
 
-    while num != 0 {
-        match num % 2 == 1 {
-            true => ret.setbit(i),
-            false => ret.clrbit(i),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The per bit conversions these replaced, to check them against.
+    fn bits_of(num: &Mpz, len: usize) -> Vec<bool> {
+        (0 .. len).map(|i| num.tstbit(i)).collect()
+    }
+
+    fn from_bits(bits: &[bool]) -> Mpz {
+        let mut ret = Mpz::zero();
+        for i in 0 .. bits.len() {
+            if bits[i] {
+                ret.setbit(i);
+            }
         }
-        num /= 2;
-        i += 1;
+        ret
+    }
+
+    fn words(seed: u64, len: usize) -> Vec<u64> {
+        let mut state = seed;
+        (0 .. len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            state
+        }).collect()
+    }
+
+    fn numbers() -> Vec<Mpz> {
+        let mut ret: Vec<Mpz> = ["0", "1", "5", "18446744073709551615", "18446744073709551616",
+                                 "12345678901234567890123456789"].iter()
+            .map(|text| Mpz::from_str_radix(text, 10).unwrap()).collect();
+
+        for seed in 1 .. 6 {
+            let mut num = Mpz::zero();
+            import_words(&mut num, &words(seed, seed as usize));
+            ret.push(num);
+        }
+
+        let negative: Vec<Mpz> = ret.iter().map(|num| -num.clone()).collect();
+        ret.extend(negative);
+        ret
+    }
+
+    #[test]
+    fn import_words_matches_setting_bits() {
+        for len in 0 .. 5 {
+            let words = words(len as u64 + 7, len);
+            let bits: Vec<bool> = (0 .. len * 64).map(|i| words[i / 64] & (1 << (i % 64)) != 0).collect();
+
+            // reused memory, even of a negative number, is overwritten
+            let mut num = Mpz::from_str_radix("-123456789012345678901234567890", 10).unwrap();
+            import_words(&mut num, &words);
+
+            assert_eq!(num, from_bits(&bits));
+            assert_eq!(boolvec_to_words(&bits), words);
+            assert_eq!(boolvec_to_bignum(&bits), num);
+        }
+    }
+
+    #[test]
+    fn limbs_match_bits_of_the_magnitude() {
+        for num in numbers() {
+            let magnitude = num.abs();
+            let count = limb_count(&num);
+            // gmp counts zero as one bit long, but with no limbs
+            match magnitude == Mpz::zero() {
+                true  => assert_eq!(count, 0),
+                false => assert_eq!(count, (magnitude.bit_length() + LIMB_BITS - 1) / LIMB_BITS),
+            }
+
+            for n in 0 .. count + 2 {
+                let bits: Vec<bool> = (0 .. LIMB_BITS).map(|i| magnitude.tstbit(n * LIMB_BITS + i)).collect();
+                assert_eq!(limb(&num, n), boolvec_to_usize(&bits), "limb {} of {}", n, num);
+            }
+        }
+    }
+
+    #[test]
+    fn shift_right_matches_bits() {
+        for num in numbers() {
+            for &bits in &[0, 1, 3, 63, 64, 65, 100, 200] {
+                let mut shifted = num.clone();
+                shift_right(&mut shifted, bits);
+
+                let len = num.bit_length() + 70;
+                let expected: Vec<bool> = (0 .. len).map(|i| num.tstbit(i + bits)).collect();
+                assert_eq!(bits_of(&shifted, len), expected, "{} >> {}", num, bits);
+                assert_eq!(shifted < Mpz::zero(), num < Mpz::zero());
+            }
+        }
+    }
+
+    #[test]
+    fn truncate_bits_matches_bits() {
+        for num in numbers() {
+            for &width in &[0, 1, 7, 8, 63, 64, 65, 100, 129] {
+                let mut truncated = num.clone();
+                truncate_bits(&mut truncated, width);
+
+                assert!(truncated >= Mpz::zero());
+                assert!(truncated.bit_length() <= width.max(1));
+                assert_eq!(truncated, from_bits(&bits_of(&num, width)), "{} mod 2^{}", num, width);
+            }
+        }
+    }
+
+    #[test]
+    fn bignum_conversions_match_bits() {
+        for num in numbers() {
+            assert_eq!(bignum_to_boolvec(&num), bits_of(&num, num.bit_length()), "{}", num);
+
+            match (num < Mpz::zero(), limb_count(&num)) {
+                (false, 0) | (false, 1) => assert_eq!(bignum_to_usize(&num).unwrap(), limb(&num, 0)),
+                _ => assert!(bignum_to_usize(&num).is_err(), "{}", num),
+            }
+        }
+
+        let max = Mpz::from_str_radix("18446744073709551615", 10).unwrap();
+        assert_eq!(bignum_to_usize(&max).unwrap(), boolvec_to_usize(&bits_of(&max, 64)));
+        assert!(bignum_to_usize(&(max.clone() + Mpz::one())).is_err());
+
+        for text in &["-1", "-5", "-18446744073709551615", "-18446744073709551616"] {
+            assert!(bignum_to_usize(&Mpz::from_str_radix(text, 10).unwrap()).is_err(), "{}", text);
+        }
+        assert_eq!(bignum_to_usize(&-Mpz::zero()).unwrap(), 0);
     }
-    ret
 }
@@ -10,6 +10,7 @@ use super::util::*;
 use super::error::*;
 use super::garden::Garden;
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Boolvec (Vec<bool>),
     Bignum (gmp::mpz::Mpz),
@@ -21,17 +22,22 @@ pub enum Value {
     },
 }
 
+// Values are written the way they are parsed, so that instructions can be
+// turned back into source.
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match *self {
-		    Value::Boolvec(ref vec) => write!(f, "v{}", boolvec_to_bignum(vec)),
-			Value::Bignum(ref vec) => write!(f, "n{}", vec),
+		    Value::Boolvec(ref vec) => {
+				let bits: String = vec.iter().map(|b| if *b { '1' } else { '0' }).collect();
+				write!(f, "b{}", bits)
+			},
+			Value::Bignum(ref num) => write!(f, "{}", num),
             Value::Pointer {ref pos, ref len, ref rev} => {
 				let negative_str = match *rev {
                     true => "-",
                     false => "",
                 };
-				write!(f, "[{}{}:{}]", negative_str, pos, len)
+				write!(f, "[{}{}; {}]", negative_str, pos, len)
 			},
 			Value::Position(ref ptr, rev) => {
 				write!(f, "{}{}", match rev{
@@ -53,113 +59,125 @@ impl Value {
         }
     }
 
-    pub fn get_ptr_position(&self, garden: &Garden) -> usize {
+    pub fn get_ptr_position(&self, garden: &Garden) -> Result<usize, ErrorType> {
         match *self {
             Value::Pointer {
                 ref pos, ref rev, ..
-            } => match *rev {
-                true => garden.stack_len() - pos.get_usize(garden),
-                false => pos.get_usize(garden)
+            } => {
+                let pos = pos.get_usize(garden)?;
+                match *rev {
+                    true => match garden.stack_len().checked_sub(pos) {
+                        Some(pos) => Ok(pos),
+                        None => Err(ErrorType::OutOfBounds {
+                            start: pos,
+                            end: pos,
+                            len: garden.stack_len(),
+                        }),
+                    },
+                    false => Ok(pos)
+                }
             },
-            _ => panic!("Value, {}, is not a pointer!", self)
+            _ => Err(ErrorType::InvalidPointer(self.clone()))
         }
     }
 
-    pub fn get_usize(&self, garden: &Garden) -> usize {
-        bignum_to_usize(&self.get_bignum(garden))
+    pub fn get_usize(&self, garden: &Garden) -> Result<usize, ErrorType> {
+        bignum_to_usize(&self.get_bignum(garden)?)
     }
 
-    pub fn get_bignum(&self, garden: &Garden) -> gmp::mpz::Mpz {
-        match *self {
+    pub fn get_bignum(&self, garden: &Garden) -> Result<gmp::mpz::Mpz, ErrorType> {
+        Ok(match *self {
             Value::Bignum(ref num) => num.clone(),
             Value::Boolvec(ref vec) => boolvec_to_bignum(vec),
             Value::Pointer {..} => {
-                let size = self.get_ptr_size(garden);
-                let pos  = self.get_ptr_position(garden);
-                let bits = garden.slice(pos, pos + size);
-
-                boolvec_to_bignum(bits)
+                let mut ret = gmp::mpz::Mpz::new();
+                self.load_bignum(garden, &mut ret)?;
+                ret
             },
             Value::Position(ref ptr, rev) => {
-                let mut pos = ptr.get_ptr_position(garden);
-                if rev {
-                    pos += ptr.get_ptr_size(garden);
-                }
+                let pos = Value::position(ptr, rev, garden)?;
                 usize_to_bignum(pos)
             }
-        }
+        })
     }
 
-    pub fn get_boolvec(&self, garden: &Garden) -> Vec<bool> {
+    // Like 'get_bignum', but reuses the memory 'out' already holds.
+    pub fn load_bignum(&self, garden: &Garden, out: &mut gmp::mpz::Mpz) -> Result<(), ErrorType> {
         match *self {
+            Value::Bignum(ref num) => out.set(num),
+            Value::Pointer {..} => {
+                let size = self.get_ptr_size(garden)?;
+                let pos  = self.get_ptr_position(garden)?;
+
+                garden.load_bignum(pos, size, out)?;
+            },
+            _ => *out = self.get_bignum(garden)?,
+        }
+        Ok(())
+    }
+
+    pub fn get_boolvec(&self, garden: &Garden) -> Result<Vec<bool>, ErrorType> {
+        Ok(match *self {
             Value::Bignum(ref num) => bignum_to_boolvec(num),
             Value::Boolvec(ref vec) => vec.clone(),
             Value::Pointer {..} => {
-                let size = self.get_ptr_size(garden);
-                let pos  = self.get_ptr_position(garden);
-                let bits = garden.slice(pos, pos + size);
+                let size = self.get_ptr_size(garden)?;
+                let pos  = self.get_ptr_position(garden)?;
+                let bits = garden.slice(pos, garden.end_of(pos, size)?)?;
 
                 bits.to_vec()
             },
             Value::Position(ref ptr, rev) => {
-                let mut pos = ptr.get_ptr_position(garden);
-                if rev {
-                    pos += ptr.get_ptr_size(garden);
-                }
+                let pos = Value::position(ptr, rev, garden)?;
                 usize_to_boolvec(pos)
             }
+        })
+    }
+
+    // Where '<ptr' points, or '>ptr' with 'rev': the start or the end of it.
+    fn position(ptr: &Value, rev: bool, garden: &Garden) -> Result<usize, ErrorType> {
+        let pos = ptr.get_ptr_position(garden)?;
+        match rev {
+            true  => garden.end_of(pos, ptr.get_ptr_size(garden)?),
+            false => Ok(pos),
         }
     }
 
-    pub fn get_ptr_size(&self, garden: &Garden) -> usize {
+    pub fn get_ptr_size(&self, garden: &Garden) -> Result<usize, ErrorType> {
         match *self {
             Value::Pointer {ref len, ..} => len.get_usize(garden),
-            _ => panic!("Value, {}, is not a pointer!", self)
+            _ => Err(ErrorType::InvalidPointer(self.clone()))
         }
     }
 
-    pub fn can_coerce(&self, new_size: usize, garden: &Garden) -> bool {
-        match *self {
-            Value::Pointer {ref len, ..} => len.get_usize(garden) <= new_size,
-            Value::Boolvec(ref vec) => vec.len() <= new_size,
-            Value::Bignum(ref num) => num.bit_length() <= new_size,
-            Value::Position(ref ptr, rev) => {
-                let mut pos = ptr.get_ptr_position(garden);
-                if rev {
-                    pos += ptr.get_ptr_size(garden);
-                }
-                usize_len(pos) <= new_size
-            }
-        }
+    pub fn can_coerce(&self, new_size: usize, garden: &Garden) -> Result<bool, ErrorType> {
+        Ok(self.get_size(garden)? <= new_size)
     }
 
-    pub fn get_size(&self, garden: &Garden) -> usize {
-        match *self {
-            Value::Pointer {ref len, ..} => len.get_usize(garden),
+    pub fn get_size(&self, garden: &Garden) -> Result<usize, ErrorType> {
+        Ok(match *self {
+            Value::Pointer {ref len, ..} => len.get_usize(garden)?,
             Value::Boolvec(ref vec) => vec.len(),
             Value::Bignum(ref num) => num.bit_length(),
             Value::Position(ref ptr, rev) => {
-                let mut pos = ptr.get_ptr_position(garden) ;
-				if rev {
-					pos += ptr.get_ptr_size(garden);
-				}
+                let pos = Value::position(ptr, rev, garden)?;
                 usize_len(pos)
             }
-        }
+        })
     }
 
-    pub fn new(value: &str, err: &Error, require_pointer: bool) -> Value {
-        let ret = match Value::create(value, err) {
+    pub fn new(value: &str, err: &Error, require_pointer: bool) -> Result<Value, TaterError> {
+        let ret = match Value::create(value, err)? {
             Some(ret) => ret,
-            None => err.throw(ErrorType::InvalidValue(value.to_string()))
+            None => return Err(err.error(ErrorType::InvalidValue(value.to_string())))
         };
         if require_pointer && !ret.is_ptr() {
-            err.throw(ErrorType::InvalidPointer(ret))
+            return Err(err.error(ErrorType::InvalidPointer(ret)))
         };
-        ret
+        Ok(ret)
     }
 
-    fn create(value: &str, err: &Error) -> Option<Value> {
+    fn create(value: &str, err: &Error) -> Result<Option<Value>, TaterError> {
         let value = value.trim();
 
         let re_ptr = Regex::new(r"^\[(.*?)\]$").unwrap();
@@ -181,7 +199,7 @@ impl Value {
                 }
 
                 if c == '[' {
-                    bracket_n -= 1;
+                    bracket_n += 1;
                 } else if c == ']' {
                     bracket_n -= 1;
                 }
@@ -203,10 +221,10 @@ impl Value {
                 args.1 = "1".to_string();
             }
 
-            let position = Value::create(&args.0, err);
-            let length = Value::create(&args.1, err);
+            let position = Value::create(&args.0, err)?;
+            let length = Value::create(&args.1, err)?;
 
-            return match (position, length) {
+            return Ok(match (position, length) {
                 (Some(pos_val), Some(len_val)) => {
                     Some(Value::Pointer {
                         pos: Box::new(pos_val),
@@ -215,7 +233,7 @@ impl Value {
                     })
                 },
                 _ => None
-            }
+            })
         } else if value.chars().next() == Some('b') {
             let mut boolvec: Vec<bool> = vec![];
             boolvec.reserve(value.len() - 1);
@@ -224,42 +242,42 @@ impl Value {
                 boolvec.push(match c {
                     '0' => false,
                     '1' => true,
-                    other => err.throw(ErrorType::Generic(
+                    _ => return Err(err.error(ErrorType::Generic(
                         format!("'{}' is invalid for boolvec!", c)
-                    ))
+                    )))
                 });
             }
 
-            return Some(Value::Boolvec(boolvec));
+            return Ok(Some(Value::Boolvec(boolvec)));
 
         } else if value.chars().next() == Some('<') {
-            return match Value::create(&value[1 ..], err) {
+            return Ok(match Value::create(&value[1 ..], err)? {
                 Some(val) => {
                     if !val.is_ptr() {
-                        err.throw(ErrorType::InvalidPointer(val));
+                        return Err(err.error(ErrorType::InvalidPointer(val)));
                     }
                     Some(Value::Position(Box::new(val), false))
                 }
                 None => None
-            }
+            })
 
         } else if value.chars().next() == Some('>') {
-            return match Value::create(&value[1 ..], err) {
+            return Ok(match Value::create(&value[1 ..], err)? {
                 Some(val) => {
                     if !val.is_ptr() {
-                        err.throw(ErrorType::InvalidPointer(val));
+                        return Err(err.error(ErrorType::InvalidPointer(val)));
                     }
                     Some(Value::Position(Box::new(val), true))
                 }
                 None => None
-            }
+            })
 
         } else {
             // non-pointer value
-            match gmp::mpz::Mpz::from_str(value) {
+            Ok(match gmp::mpz::Mpz::from_str(value) {
                 Ok(val) => Some(Value::Bignum(val)),
                 Err(_) => None,
-            }
+            })
         }
     }
 }